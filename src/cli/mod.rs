@@ -4,6 +4,8 @@ use clap::{Args, Command, Parser, Subcommand, ValueHint};
 use clap_complete::{generate, Generator, Shell};
 use log::LevelFilter;
 
+use crate::show::Format;
+
 // CLI =================================================
 // #[derive(Parser, Clone)]
 #[derive(Parser, Debug, PartialEq)]
@@ -29,6 +31,14 @@ pub struct Cli {
     #[arg(short, long)]
     pub config_file: Option<String>,
 
+    /// profile of the config file to use, e.g. `production`, defaults to `$IOC_PROFILE`
+    #[arg(short = 'P', long, global = true)]
+    pub profile: Option<String>,
+
+    /// override a setting, e.g. `--set filesystem.deploy=/tmp/ioc`, can be repeated
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    pub set: Vec<String>,
+
     /// The name of the command
     #[command(subcommand)]
     pub command: Option<Commands>,
@@ -63,8 +73,14 @@ pub enum Commands {
     Install(InstallCommand),
     /// uninstall ioc definitions
     Uninstall(UninstallCommand),
-    /// stage a single ioc definition (for development and testing)
+    /// stage ioc definitions (for development and testing)
     Stage(StageCommand),
+    /// render a template for an ioc definition and print the result
+    Render(RenderCommand),
+    /// inspect the configuration
+    Config(ConfigCommand),
+    /// check the configuration and environment the tool depends on
+    Doctor,
 }
 
 #[derive(Args, Debug, Clone, PartialEq)]
@@ -81,6 +97,10 @@ pub struct InstallCommand {
     #[arg(short, long, action)]
     pub force: bool,
 
+    /// don't ask for confirmation, even if the profile requires it
+    #[arg(short, long, action)]
+    pub yes: bool,
+
     /// list of IOCs to deploy, space separated
     #[clap(value_parser, num_args = 1.., value_delimiter = ' ')]
     pub iocs: Option<Vec<String>>,
@@ -95,12 +115,57 @@ pub struct UninstallCommand {
 
 #[derive(Args, Debug, Clone, PartialEq)]
 pub struct StageCommand {
-    /// single IOC DIRECTORY to stage
-    #[clap(value_hint = ValueHint::DirPath)]
-    pub ioc: String,
-    /// optional staging directory
+    /// list of IOCs to stage, space separated
+    #[clap(value_parser, num_args = 1.., value_delimiter = ' ', value_hint = ValueHint::DirPath)]
+    pub iocs: Option<Vec<String>>,
+    /// optional staging directory, a single IOC is staged into exactly this directory, several
+    /// IOCs into directories named after them below it
     #[arg(short, long, value_hint = ValueHint::DirPath)]
     pub path: Option<String>,
+    /// print a tree of the staged files
+    #[arg(long, action)]
+    pub tree: bool,
+    /// remove previous stages from the staging directory, or the stage root, first
+    #[arg(long, action)]
+    pub clean: bool,
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct RenderCommand {
+    /// single IOC DIRECTORY to render the template for
+    #[clap(value_hint = ValueHint::DirPath)]
+    pub ioc: String,
+    /// template to render, a site template or a `.tera` file of the IOC definition
+    #[arg(short, long, default_value = "startup.tera")]
+    pub template: String,
+    /// print the context passed to the template engine as JSON
+    #[arg(long, action)]
+    pub context: bool,
+    /// template glob to use instead of the configured `app.template_directory`
+    #[arg(long)]
+    pub templates: Option<String>,
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct ConfigCommand {
+    #[command(subcommand)]
+    pub command: ConfigCommands,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ConfigCommands {
+    /// print the effective settings and where each value comes from
+    Show(ConfigShowCommand),
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct ConfigShowCommand {
+    /// IOC DIRECTORY to show the effective IOC config of as well
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    pub ioc: Option<String>,
+    /// output format
+    #[arg(long, value_enum, default_value = "toml")]
+    pub format: Format,
 }
 
 #[cfg(test)]
@@ -116,6 +181,8 @@ mod tests {
             ver: false,
             log_level: None,
             config_file: None,
+            profile: None,
+            set: Vec::new(),
             command: None,
         };
         // fallback to "Error"
@@ -140,4 +207,127 @@ mod tests {
         test_cli.log_level = Some("trace".to_string());
         assert_eq!(test_cli.get_level_filter(), LevelFilter::Trace);
     }
+
+    #[test]
+    fn render_command() {
+        let cli = Cli::parse_from(["ioc", "render", "IOC01"]);
+        match cli.command {
+            Some(Commands::Render(args)) => {
+                assert_eq!(args.ioc, "IOC01");
+                assert_eq!(args.template, "startup.tera");
+                assert!(!args.context);
+                assert_eq!(args.templates, None);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+
+        let cli = Cli::parse_from([
+            "ioc",
+            "render",
+            "IOC01",
+            "--template",
+            "st.cmd.tera",
+            "--context",
+            "--templates",
+            "new/**/*.tera",
+        ]);
+        match cli.command {
+            Some(Commands::Render(args)) => {
+                assert_eq!(args.template, "st.cmd.tera");
+                assert!(args.context);
+                assert_eq!(args.templates, Some("new/**/*.tera".to_string()));
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+    }
+
+    #[test]
+    fn install_command() {
+        let cli = Cli::parse_from(["ioc", "install", "IOC01", "--profile", "production", "-y"]);
+        assert_eq!(cli.profile, Some("production".to_string()));
+        match cli.command {
+            Some(Commands::Install(args)) => {
+                assert_eq!(args.iocs, Some(vec!["IOC01".to_string()]));
+                assert!(args.yes);
+                assert!(!args.dryrun);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+
+        let cli = Cli::parse_from([
+            "ioc",
+            "-P",
+            "dev",
+            "install",
+            "--set",
+            "filesystem.deploy=/tmp/ioc",
+            "--set",
+            "policy.confirm=false",
+        ]);
+        assert_eq!(cli.profile, Some("dev".to_string()));
+        assert_eq!(
+            cli.set,
+            vec!["filesystem.deploy=/tmp/ioc", "policy.confirm=false"]
+        );
+    }
+
+    #[test]
+    fn config_show_command() {
+        let cli = Cli::parse_from(["ioc", "config", "show"]);
+        match cli.command {
+            Some(Commands::Config(ConfigCommand {
+                command: ConfigCommands::Show(args),
+            })) => {
+                assert_eq!(args.ioc, None);
+                assert_eq!(args.format, Format::Toml);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+
+        let cli = Cli::parse_from([
+            "ioc", "config", "show", "--ioc", "IOC01", "--format", "json",
+        ]);
+        match cli.command {
+            Some(Commands::Config(ConfigCommand {
+                command: ConfigCommands::Show(args),
+            })) => {
+                assert_eq!(args.ioc, Some("IOC01".to_string()));
+                assert_eq!(args.format, Format::Json);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+    }
+
+    #[test]
+    fn doctor_command() {
+        let cli = Cli::parse_from(["ioc", "--profile", "production", "doctor"]);
+        assert_eq!(cli.command, Some(Commands::Doctor));
+        assert_eq!(cli.profile, Some("production".to_string()));
+    }
+
+    #[test]
+    fn stage_command() {
+        let cli = Cli::parse_from(["ioc", "stage", "IOC01", "IOC02", "-p", "out", "--tree"]);
+        match cli.command {
+            Some(Commands::Stage(args)) => {
+                assert_eq!(
+                    args.iocs,
+                    Some(vec!["IOC01".to_string(), "IOC02".to_string()])
+                );
+                assert_eq!(args.path, Some("out".to_string()));
+                assert!(args.tree);
+                assert!(!args.clean);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+
+        let cli = Cli::parse_from(["ioc", "stage", "--clean"]);
+        match cli.command {
+            Some(Commands::Stage(args)) => {
+                assert_eq!(args.iocs, None);
+                assert!(args.clean);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+    }
 }
@@ -0,0 +1,373 @@
+use std::path::Path;
+use std::{env, fmt};
+
+use colored::Colorize;
+use git2::Repository;
+
+use crate::log_macros::{cross, exclaim, tick};
+use crate::origin;
+use crate::settings::{self, Settings, VerifyError};
+use crate::stage::run_dir;
+
+/// free space below which a deployment may fail
+const DISK_SPACE_WARN: u64 = 512 * 1024 * 1024;
+/// free space below which a deployment will likely fail
+const DISK_SPACE_FAIL: u64 = 50 * 1024 * 1024;
+
+/// outcome of a check, ordered by severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// a single item of the checklist
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = match self.status {
+            Status::Pass => tick!(),
+            Status::Warn => exclaim!(),
+            Status::Fail => cross!(),
+        };
+        write!(f, "{} {:<14} {}", mark, self.name, self.detail)
+    }
+}
+
+/// Check everything the tool depends on, as far as possible even if parts of the configuration
+/// are broken: the config files, the settings and templates, the stage, deploy and shellbox
+/// roots, the free disk space, the git repository of the current directory and the identity
+/// of the user.
+pub fn doctor(config_file: &str, profile: Option<&str>, sets: &[String]) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let config = match Settings::layers(config_file, profile, sets)
+        .and_then(|layers| Ok((settings::merge(&layers)?, layers)))
+    {
+        Ok((config, layers)) => {
+            let origins: Vec<&str> = layers.iter().map(|l| l.origin.as_str()).collect();
+            checks.push(Check::new("config files", Status::Pass, origins.join(", ")));
+            Some(config)
+        }
+        Err(e) => {
+            checks.push(Check::new("config files", Status::Fail, e.to_string()));
+            None
+        }
+    };
+
+    if let Some(config) = config {
+        let (settings, errors) = Settings::read(&config, profile);
+        let (templates, other): (Vec<VerifyError>, Vec<VerifyError>) =
+            errors.into_iter().partition(VerifyError::is_template);
+        checks.push(match other.is_empty() {
+            true => Check::new(
+                "settings",
+                Status::Pass,
+                match profile {
+                    Some(profile) => format!("profile {}", profile),
+                    None => "no profile".to_string(),
+                },
+            ),
+            false => Check::new("settings", Status::Fail, join(&other)),
+        });
+        checks.push(match templates.is_empty() {
+            true => Check::new(
+                "templates",
+                Status::Pass,
+                settings.app.template_directory.clone(),
+            ),
+            false => Check::new("templates", Status::Fail, join(&templates)),
+        });
+        checks.extend(directories(
+            &settings,
+            config.get::<String>("filesystem.stage").ok(),
+        ));
+        checks.push(disk_space(&settings));
+        checks.push(git(&settings));
+    }
+    checks.push(user());
+    checks
+}
+
+/// Print the checklist, the exit status is 1 if a check failed.
+pub fn report(checks: &[Check]) -> i32 {
+    for check in checks {
+        println!("{}", check);
+    }
+    match checks.iter().map(|c| c.status).max() {
+        Some(Status::Fail) => 1,
+        _ => 0,
+    }
+}
+
+fn join(errors: &[VerifyError]) -> String {
+    let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+    errors.join("; ")
+}
+
+/// the stage, deploy and shellbox roots, `stage` is the configured stage root
+fn directories(settings: &Settings, stage: Option<String>) -> Vec<Check> {
+    let problems = settings.check_directories();
+    let problem = |key: &str| {
+        problems.iter().find_map(|e| match e {
+            VerifyError::Directory { key: k, reason, .. } if k == key => Some(reason.clone()),
+            _ => None,
+        })
+    };
+    let filesystem = &settings.filesystem;
+
+    let stage_root = Path::new(&filesystem.stage);
+    let stage_check = match problem("filesystem.stage") {
+        Some(reason) => Check::new(
+            "stage root",
+            Status::Fail,
+            format!("{:?}: {}", stage_root, reason),
+        ),
+        None => match stage_root
+            .exists()
+            .then(|| run_dir::verify_stage_root(stage_root))
+        {
+            Some(Err(e)) => Check::new("stage root", Status::Fail, e.to_string()),
+            _ => match stage {
+                // the default is below XDG_RUNTIME_DIR too
+                Some(raw) if !raw.contains("XDG_RUNTIME_DIR") => {
+                    Check::new("stage root", Status::Pass, format!("{:?}", stage_root))
+                }
+                _ if env::var_os("XDG_RUNTIME_DIR").is_none() => Check::new(
+                    "stage root",
+                    Status::Warn,
+                    format!("XDG_RUNTIME_DIR is not set, using {:?}", stage_root),
+                ),
+                _ => Check::new("stage root", Status::Pass, format!("{:?}", stage_root)),
+            },
+        },
+    };
+
+    let root = |name: &'static str, key: &str, path: &str| match problem(key) {
+        Some(reason) => Check::new(name, Status::Fail, format!("{:?}: {}", path, reason)),
+        None => Check::new(name, Status::Pass, format!("{:?} is writable", path)),
+    };
+    vec![
+        stage_check,
+        root("deploy root", "filesystem.deploy", &filesystem.deploy),
+        root("shellbox root", "filesystem.shellbox", &filesystem.shellbox),
+    ]
+}
+
+/// free space for the stage and the deployment
+fn disk_space(settings: &Settings) -> Check {
+    let mut status = Status::Pass;
+    let mut details = Vec::new();
+    for (name, path) in [
+        ("stage", &settings.filesystem.stage),
+        ("deploy", &settings.filesystem.deploy),
+    ] {
+        match available_space(Path::new(path)) {
+            Ok(available) => {
+                let level = match available {
+                    a if a < DISK_SPACE_FAIL => Status::Fail,
+                    a if a < DISK_SPACE_WARN => Status::Warn,
+                    _ => Status::Pass,
+                };
+                status = status.max(level);
+                details.push(format!("{} {} free", name, human_size(available)));
+            }
+            Err(e) => {
+                status = status.max(Status::Warn);
+                details.push(format!("{} unknown: {}", name, e));
+            }
+        }
+    }
+    Check::new("disk space", status, details.join(", "))
+}
+
+/// bytes available to the user on the file system of `path`, or of its closest existing parent
+fn available_space(path: &Path) -> nix::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists() && !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let stat = nix::sys::statvfs::statvfs(existing)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, units[0]),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
+
+/// the git repository of the current directory, the default source of `ioc install`
+fn git(settings: &Settings) -> Check {
+    let current_dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => return Check::new("git", Status::Fail, e.to_string()),
+    };
+    match Repository::discover(&current_dir) {
+        Ok(repo) => {
+            let workdir = repo.workdir().unwrap_or(repo.path()).display().to_string();
+            match settings.policy.require_tag && origin::head_tag(&current_dir).is_none() {
+                true => Check::new(
+                    "git",
+                    Status::Warn,
+                    format!(
+                        "{}, HEAD is not tagged but the policy requires tags",
+                        workdir
+                    ),
+                ),
+                false => Check::new("git", Status::Pass, workdir),
+            }
+        }
+        Err(_) => Check::new(
+            "git",
+            Status::Warn,
+            format!(
+                "{:?} is not in a git repository, ORIGIN won't record a commit",
+                current_dir
+            ),
+        ),
+    }
+}
+
+/// the name of the user, recorded in ORIGIN
+fn user() -> Check {
+    match users::get_current_username() {
+        Some(name) => Check::new("user", Status::Pass, name.to_string_lossy()),
+        None => match env::var("USER") {
+            Ok(name) => Check::new(
+                "user",
+                Status::Warn,
+                format!(
+                    "uid {} has no user name, using $USER {}",
+                    users::get_current_uid(),
+                    name
+                ),
+            ),
+            Err(_) => Check::new(
+                "user",
+                Status::Fail,
+                format!(
+                    "uid {} has no user name and $USER is not set",
+                    users::get_current_uid()
+                ),
+            ),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::fs;
+    use tempfile::tempdir;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    /// site config with the repository templates and the roots below `dir`
+    fn site_config(dir: &Path, deploy: &Path) -> String {
+        let config = dir.join("ioc.toml");
+        fs::write(
+            &config,
+            format!(
+                "[filesystem]\nstage = {:?}\ndeploy = {:?}\nshellbox = {:?}\n\n\
+                 [app]\ntemplate_directory = \"templates/**/*.tera\"\n",
+                dir.join("stage"),
+                deploy,
+                dir.join("hosts"),
+            ),
+        )
+        .unwrap();
+        config.to_str().unwrap().to_string()
+    }
+
+    fn status(checks: &[Check], name: &str) -> Status {
+        checks.iter().find(|c| c.name == name).unwrap().status
+    }
+
+    #[test]
+    #[serial]
+    fn healthy() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("deploy")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("hosts")).unwrap();
+        let config = site_config(temp_dir.path(), &temp_dir.path().join("deploy"));
+
+        let checks = doctor(&config, None, &[]);
+        for name in [
+            "config files",
+            "settings",
+            "templates",
+            "stage root",
+            "deploy root",
+            "shellbox root",
+        ] {
+            assert_eq!(status(&checks, name), Status::Pass, "{:?}", checks);
+        }
+        assert_ne!(status(&checks, "disk space"), Status::Fail);
+        assert_eq!(status(&checks, "git"), Status::Pass);
+    }
+
+    #[test]
+    #[serial]
+    fn problems() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("hosts")).unwrap();
+        let config = site_config(temp_dir.path(), &temp_dir.path().join("missing"));
+
+        let checks = doctor(
+            &config,
+            None,
+            &["app.template_directory=none/*.tera".to_string()],
+        );
+        assert_eq!(status(&checks, "settings"), Status::Pass);
+        assert_eq!(status(&checks, "templates"), Status::Fail);
+        assert_eq!(status(&checks, "deploy root"), Status::Fail);
+        assert_eq!(status(&checks, "shellbox root"), Status::Pass);
+        assert_eq!(report(&checks), 1);
+
+        let checks = doctor(&config, Some("missing"), &[]);
+        assert_eq!(status(&checks, "config files"), Status::Fail);
+        assert_eq!(checks.len(), 2);
+    }
+
+    #[test]
+    fn exit_status() {
+        let pass = Check::new("user", Status::Pass, "");
+        let warn = Check::new("git", Status::Warn, "");
+        assert_eq!(report(&[pass.clone(), warn]), 0);
+        let fail = Check::new("disk space", Status::Fail, "");
+        assert_eq!(report(&[pass, fail]), 1);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert!(available_space(Path::new("does/not/exist")).is_ok());
+    }
+}
@@ -1,10 +1,122 @@
 use crate::log_macros::exclaim;
 use colored::Colorize;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use log::warn;
-use std::path::Path;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
 use std::{fs, io};
 
+/// directories kept intact when flattening, unless configured otherwise
+pub const DEFAULT_PRESERVED_DIRS: [&str; 1] = ["cfg"];
+
+/// ignore file of an IOC definition, gitignore syntax
+pub const IGNORE_FILE: &str = ".iocignore";
+
+/// layout of the deployed IOC
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// flatten all files, except for the preserved directories
+    #[default]
+    Flat,
+    /// keep the directory structure of the IOC definition
+    Preserve,
+}
+
+/// layout settings, of an IOC config or the site default
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct LayoutSettings {
+    pub layout: Option<Layout>,
+    /// directories kept intact by the flat layout
+    pub preserve: Option<Vec<String>>,
+    /// copy symbolic links or what they point to
+    pub symlinks: Option<Symlinks>,
+    /// handling of file names which aren't valid UTF-8
+    pub non_utf8_names: Option<NamePolicy>,
+}
+
+impl LayoutSettings {
+    /// the built-in defaults
+    pub fn builtin() -> LayoutSettings {
+        LayoutSettings {
+            layout: Some(Layout::default()),
+            preserve: Some(DEFAULT_PRESERVED_DIRS.map(String::from).to_vec()),
+            symlinks: Some(Symlinks::default()),
+            non_utf8_names: Some(NamePolicy::default()),
+        }
+    }
+
+    /// these settings, anything not set is taken from `defaults`
+    pub fn or(&self, defaults: &LayoutSettings) -> LayoutSettings {
+        LayoutSettings {
+            layout: self.layout.or(defaults.layout),
+            preserve: self.preserve.clone().or(defaults.preserve.clone()),
+            symlinks: self.symlinks.or(defaults.symlinks),
+            non_utf8_names: self.non_utf8_names.or(defaults.non_utf8_names),
+        }
+    }
+
+    pub fn copy_options(&self) -> CopyOptions {
+        CopyOptions {
+            symlinks: self.symlinks.unwrap_or_default(),
+            non_utf8_names: self.non_utf8_names.unwrap_or_default(),
+        }
+    }
+
+    pub fn copy_mode(&self) -> CopyMode {
+        match self.layout.unwrap_or_default() {
+            Layout::Preserve => CopyMode::Preserve,
+            Layout::Flat => match &self.preserve {
+                Some(dirs) => CopyMode::FlattenExcept(dirs.clone()),
+                None => CopyMode::preserve_directories(),
+            },
+        }
+    }
+}
+
+/// files excluded from staging: the site default patterns, followed by the `.iocignore` of
+/// the IOC definition. Both use gitignore semantics, so `.iocignore` may negate site patterns.
 #[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl IgnoreRules {
+    /// ignore nothing
+    pub fn none() -> Self {
+        Self {
+            matcher: Gitignore::empty(),
+        }
+    }
+
+    /// rules for the IOC definition in `root`
+    pub fn new(root: impl AsRef<Path>, site_patterns: &[String]) -> io::Result<Self> {
+        let root = root.as_ref();
+        let invalid = |e: ignore::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in site_patterns {
+            builder.add_line(None, pattern).map_err(invalid)?;
+        }
+        let ignore_file = root.join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            if let Some(e) = builder.add(&ignore_file) {
+                return Err(invalid(e));
+            }
+        }
+        Ok(Self {
+            matcher: builder.build().map_err(invalid)?,
+        })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.matcher.matched(path, is_dir).is_ignore()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum CopyMode {
     /// Copy directory structure as-is
     Preserve,
@@ -14,15 +126,39 @@ pub enum CopyMode {
 
 impl CopyMode {
     pub fn preserve_directories() -> Self {
-        Self::FlattenExcept(vec!["cfg".to_string()])
+        Self::FlattenExcept(DEFAULT_PRESERVED_DIRS.map(String::from).to_vec())
+    }
+
+    /// whether the directory `dir` keeps its structure
+    pub fn keeps(&self, dir: impl AsRef<OsStr>) -> bool {
+        match self {
+            Self::Preserve => true,
+            Self::FlattenExcept(dirs) => dirs.iter().any(|d| OsStr::new(d) == dir.as_ref()),
+        }
+    }
+
+    /// keep the structure of `dir` in addition to the already preserved directories
+    pub fn preserving(self, dir: &str) -> Self {
+        match self {
+            Self::Preserve => Self::Preserve,
+            Self::FlattenExcept(mut dirs) => {
+                if !dirs.iter().any(|d| d == dir) {
+                    dirs.push(dir.to_string());
+                }
+                Self::FlattenExcept(dirs)
+            }
+        }
     }
 }
 
-fn should_skip_entry(entry_name: &str, destination_parent: &Path) -> bool {
+fn should_skip_entry(entry_name: &OsStr, destination_parent: &Path) -> bool {
     // Skip if destination is in the source directory (prevent recursion)
-    if entry_name.contains(&destination_parent.to_string_lossy().as_ref()) {
+    if entry_name
+        .to_string_lossy()
+        .contains(destination_parent.to_string_lossy().as_ref())
+    {
         warn!(
-            "{} skipping recursion: {} --> {:?}",
+            "{} skipping recursion: {:?} --> {:?}",
             exclaim!(),
             entry_name,
             destination_parent
@@ -32,48 +168,291 @@ fn should_skip_entry(entry_name: &str, destination_parent: &Path) -> bool {
 
     // Skip hidden files/directories and specific unwanted directories
     let skip_patterns = [".", "__pycache__"];
-    if skip_patterns
-        .iter()
-        .any(|pattern| entry_name.starts_with(pattern))
-    {
+    if skip_patterns.iter().any(|pattern| {
+        entry_name
+            .as_encoded_bytes()
+            .starts_with(pattern.as_bytes())
+    }) {
         return true;
     }
 
     false
 }
 
-fn get_entry_name(entry: &fs::DirEntry) -> io::Result<String> {
-    entry
-        .file_name()
-        .into_string()
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 in filename"))
+/// check a file name which isn't valid UTF-8 against the policy
+fn check_entry_name(path: &Path, policy: NamePolicy) -> io::Result<()> {
+    if path.file_name().and_then(OsStr::to_str).is_some() {
+        return Ok(());
+    }
+    match policy {
+        NamePolicy::Reject => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("file name is not valid UTF-8: {}", path.display()),
+        )),
+        NamePolicy::Warn => {
+            warn!(
+                "{} file name is not valid UTF-8: {}",
+                exclaim!(),
+                path.display()
+            );
+            Ok(())
+        }
+        NamePolicy::Accept => Ok(()),
+    }
 }
 
-fn copy_directory(
-    entry_path: &Path,
-    entry_name: &str,
-    destination: &Path,
-    copy_mode: &CopyMode,
-) -> io::Result<()> {
-    match copy_mode {
-        CopyMode::Preserve => {
-            copy_recursively(entry_path, destination.join(entry_name), copy_mode.clone())
+/// how symbolic links in the source are copied
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Symlinks {
+    /// copy the file or directory the link points to
+    #[default]
+    Follow,
+    /// copy the link itself, it needs to be relative
+    Keep,
+}
+
+/// how file names which aren't valid UTF-8 are handled
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamePolicy {
+    /// fail the copy
+    Reject,
+    /// copy with a warning
+    #[default]
+    Warn,
+    /// copy silently
+    Accept,
+}
+
+/// options independent of the `CopyMode`. Mode bits and modification times are preserved.
+/// Symbolic links must not point outside of the copied tree.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CopyOptions {
+    pub symlinks: Symlinks,
+    pub non_utf8_names: NamePolicy,
+}
+
+/// a file to copy, or a link to create
+#[derive(Debug)]
+struct PlannedCopy {
+    source: PathBuf,
+    destination: PathBuf,
+    /// target of the link to create instead of copying, the canonical source path until
+    /// `CopyPlan::relink`
+    link: Option<PathBuf>,
+}
+
+/// directories to create and files to copy, in copy order
+#[derive(Debug, Default)]
+struct CopyPlan {
+    directories: Vec<PathBuf>,
+    files: Vec<PlannedCopy>,
+    /// canonical source paths of the copied files and kept directories, with their destination
+    copied: HashMap<PathBuf, PathBuf>,
+}
+
+impl CopyPlan {
+    /// Point the kept links at the destinations of their targets, which flattening may move.
+    /// Fails if a target isn't copied as is, because it is ignored or a flattened directory.
+    fn relink(&mut self) -> io::Result<()> {
+        for file in &mut self.files {
+            let Some(target) = &file.link else {
+                continue;
+            };
+            let destination = self.copied.get(target).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "symlink {:?} can't be kept, its target {:?} is ignored or flattened",
+                        file.source, target
+                    ),
+                )
+            })?;
+            let base = file.destination.parent().unwrap_or(Path::new(""));
+            file.link = Some(relative_path(destination, base));
+        }
+        Ok(())
+    }
+
+    /// source files mapped onto the same destination, each collision listed once
+    fn collisions(&self) -> Vec<(PathBuf, Vec<PathBuf>)> {
+        let mut targets: BTreeMap<&Path, Vec<PathBuf>> = BTreeMap::new();
+        for file in &self.files {
+            targets
+                .entry(file.destination.as_path())
+                .or_default()
+                .push(file.source.clone());
+        }
+        targets
+            .into_iter()
+            .filter(|(_, sources)| sources.len() > 1)
+            .map(|(destination, sources)| (destination.to_path_buf(), sources))
+            .collect()
+    }
+}
+
+/// walks the source tree and collects what to copy where
+struct Planner<'a> {
+    /// canonical source root, links must not escape it
+    root: PathBuf,
+    copy_mode: &'a CopyMode,
+    ignore: &'a IgnoreRules,
+    options: &'a CopyOptions,
+    /// canonical directories currently entered, to break cycles of followed links
+    ancestors: HashSet<PathBuf>,
+    plan: CopyPlan,
+}
+
+impl Planner<'_> {
+    fn plan_directory(
+        &mut self,
+        entry_path: &Path,
+        entry_name: &OsStr,
+        destination: &Path,
+    ) -> io::Result<()> {
+        // Keep directory structure for preserved directories, otherwise flatten: copy
+        // contents directly to destination
+        match self.copy_mode.keeps(entry_name) {
+            true => {
+                let destination = destination.join(entry_name);
+                self.plan
+                    .copied
+                    .insert(entry_path.canonicalize()?, destination.clone());
+                self.plan_recursively(entry_path, &destination)
+            }
+            false => self.plan_recursively(entry_path, destination),
+        }
+    }
+
+    fn plan_recursively(&mut self, source: &Path, destination: &Path) -> io::Result<()> {
+        let canonical = source.canonicalize()?;
+        if !self.ancestors.insert(canonical.clone()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("symlink loop at {:?}", source),
+            ));
         }
-        CopyMode::FlattenExcept(preserved_dirs) => {
-            if preserved_dirs.contains(&entry_name.to_string()) {
-                // Keep directory structure for preserved directories
-                copy_recursively(entry_path, destination.join(entry_name), copy_mode.clone())
+        self.plan.directories.push(destination.to_path_buf());
+
+        for entry in sorted_entries(source)? {
+            let entry_name = entry.file_name();
+
+            // Skip unwanted entries
+            if should_skip_entry(&entry_name, destination.parent().unwrap_or(destination)) {
+                continue;
+            }
+
+            let entry_path = entry.path();
+            let file_type = entry.file_type()?;
+            // ignored entries aren't copied, so their names and links don't matter
+            let is_dir = match file_type.is_symlink() {
+                true => entry_path.is_dir(),
+                false => file_type.is_dir(),
+            };
+            if self.ignore.is_ignored(&entry_path, is_dir) {
+                continue;
+            }
+            check_entry_name(&entry_path, self.options.non_utf8_names)?;
+            let link = match file_type.is_symlink() {
+                true => Some(self.check_link(&entry_path)?),
+                false => None,
+            };
+
+            if link.is_some() && self.options.symlinks == Symlinks::Keep {
+                self.plan.files.push(PlannedCopy {
+                    destination: destination.join(&entry_name),
+                    link,
+                    source: entry_path,
+                });
+            } else if is_dir {
+                self.plan_directory(&entry_path, &entry_name, destination)?;
             } else {
-                // Flatten: copy contents directly to destination
-                copy_recursively(entry_path, destination, copy_mode.clone())
+                let source = link.unwrap_or_else(|| canonical.join(&entry_name));
+                self.plan
+                    .copied
+                    .insert(source, destination.join(&entry_name));
+                self.plan.files.push(PlannedCopy {
+                    destination: destination.join(&entry_name),
+                    link: None,
+                    source: entry_path,
+                });
             }
         }
+        self.ancestors.remove(&canonical);
+        Ok(())
+    }
+
+    /// resolve a symlink, it must stay within the source tree. Kept links must be relative,
+    /// absolute ones would point back into the source once copied.
+    fn check_link(&self, link: &Path) -> io::Result<PathBuf> {
+        let target = fs::read_link(link)?;
+        let resolved = link.canonicalize().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("dangling symlink {:?} -> {:?}", link, target),
+            )
+        })?;
+        if !resolved.starts_with(&self.root) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "symlink {:?} -> {:?} points outside of {:?}",
+                    link, target, self.root
+                ),
+            ));
+        }
+        if self.options.symlinks == Symlinks::Keep && target.is_absolute() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("symlink {:?} -> {:?} must be relative", link, target),
+            ));
+        }
+        Ok(resolved)
     }
 }
 
-fn copy_file(source: &Path, destination: &Path) -> io::Result<()> {
-    fs::copy(source, destination)?;
-    Ok(())
+/// `path` relative to the directory `base`, both below the same destination root
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let relative: PathBuf = base
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .chain(path.components().skip(common))
+        .collect();
+    match relative.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => relative,
+    }
+}
+
+/// entries of a directory, sorted by name for a deterministic copy order
+fn sorted_entries(dir: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    Ok(entries)
+}
+
+/// copy a file with its mode bits and modification time, or create the planned link
+fn copy_file(file: &PlannedCopy) -> io::Result<()> {
+    if let Some(target) = &file.link {
+        return symlink(target, &file.destination);
+    }
+    // copies the mode bits as well
+    fs::copy(&file.source, &file.destination)?;
+    let metadata = fs::metadata(&file.source)?;
+    let times = fs::FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    fs::File::options()
+        .write(true)
+        .open(&file.destination)?
+        .set_times(times)
 }
 
 pub fn remove_dir_contents<P: AsRef<Path>>(path: P) -> io::Result<()> {
@@ -91,33 +470,74 @@ pub fn remove_dir_contents<P: AsRef<Path>>(path: P) -> io::Result<()> {
     Ok(())
 }
 
-/// Copy files from source to destination recursively.
+/// Copy files from source to destination recursively, in order of the file names, symbolic
+/// links are kept and point to where their targets are copied. Fails before copying anything if flattening maps several files onto the
+/// same destination.
 pub fn copy_recursively(
     source: impl AsRef<Path>,
     destination: impl AsRef<Path>,
     copy_mode: CopyMode,
 ) -> io::Result<()> {
-    let source = source.as_ref();
-    let destination = destination.as_ref();
-
-    fs::create_dir_all(destination)?;
-
-    for entry in fs::read_dir(source)? {
-        let entry = entry?;
-        let entry_name = get_entry_name(&entry)?;
+    copy_recursively_with(
+        source,
+        destination,
+        copy_mode,
+        &IgnoreRules::none(),
+        &CopyOptions {
+            symlinks: Symlinks::Keep,
+            non_utf8_names: NamePolicy::Accept,
+        },
+    )
+}
 
-        // Skip unwanted entries
-        if should_skip_entry(&entry_name, destination.parent().unwrap_or(destination)) {
-            continue;
-        }
+/// Like `copy_recursively`, skipping the files matched by `ignore` and handling symbolic links
+/// according to `options`.
+pub fn copy_recursively_with(
+    source: impl AsRef<Path>,
+    destination: impl AsRef<Path>,
+    copy_mode: CopyMode,
+    ignore: &IgnoreRules,
+    options: &CopyOptions,
+) -> io::Result<()> {
+    let mut planner = Planner {
+        root: source.as_ref().canonicalize()?,
+        copy_mode: &copy_mode,
+        ignore,
+        options,
+        ancestors: HashSet::new(),
+        plan: CopyPlan::default(),
+    };
+    planner
+        .plan
+        .copied
+        .insert(planner.root.clone(), destination.as_ref().to_path_buf());
+    planner.plan_recursively(source.as_ref(), destination.as_ref())?;
+    let mut plan = planner.plan;
 
-        let entry_path = entry.path();
+    let collisions = plan.collisions();
+    if !collisions.is_empty() {
+        let report: Vec<String> = collisions
+            .iter()
+            .map(|(destination, sources)| {
+                let sources: Vec<String> = sources
+                    .iter()
+                    .map(|s| format!("{:?}", s.as_path()))
+                    .collect();
+                format!("{} -> {:?}", sources.join(", "), destination.as_path())
+            })
+            .collect();
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("files collide when flattened: {}", report.join("; ")),
+        ));
+    }
 
-        if entry.file_type()?.is_dir() {
-            copy_directory(&entry_path, &entry_name, destination, &copy_mode)?;
-        } else {
-            copy_file(&entry_path, &destination.join(&entry_name))?;
-        }
+    plan.relink()?;
+    for directory in &plan.directories {
+        fs::create_dir_all(directory)?;
+    }
+    for file in &plan.files {
+        copy_file(file)?;
     }
     Ok(())
 }
@@ -211,6 +631,362 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn copy_files_collision() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("db"))?;
+        fs::create_dir_all(source_dir.join("legacy"))?;
+        fs::write(source_dir.join("db/motor.db"), "db")?;
+        fs::write(source_dir.join("legacy/motor.db"), "legacy")?;
+        fs::write(source_dir.join("st.cmd"), "st.cmd")?;
+
+        let target_dir = temp_dir.path().join("target");
+
+        let error = copy_recursively(&source_dir, &target_dir, CopyMode::preserve_directories())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        let message = error.to_string();
+        assert!(message.contains("db/motor.db"));
+        assert!(message.contains("legacy/motor.db"));
+        // nothing copied
+        assert!(!target_dir.join("st.cmd").exists());
+
+        // preserving one of the directories resolves the collision
+        copy_recursively(
+            &source_dir,
+            &target_dir,
+            CopyMode::preserve_directories().preserving("db"),
+        )?;
+        assert_eq!(fs::read_to_string(target_dir.join("motor.db"))?, "legacy");
+        assert_eq!(fs::read_to_string(target_dir.join("db/motor.db"))?, "db");
+        Ok(())
+    }
+
+    #[test]
+    fn copy_files_ignored() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("cfg"))?;
+        fs::create_dir_all(source_dir.join("test"))?;
+        fs::create_dir_all(source_dir.join("build/cfg"))?;
+        fs::write(source_dir.join("README.md"), "readme")?;
+        fs::write(source_dir.join("NOTES.md"), "notes")?;
+        fs::write(source_dir.join("st.cmd"), "st.cmd")?;
+        fs::write(source_dir.join("st.cmd~"), "backup")?;
+        fs::write(source_dir.join("test/data.txt"), "test data")?;
+        fs::write(source_dir.join("build/cfg/out.cfg"), "build output")?;
+        fs::write(source_dir.join("cfg/motors.cfg"), "motors")?;
+        fs::write(
+            source_dir.join(IGNORE_FILE),
+            "# IOC rules\n/build/\ntest/\n!README.md\n",
+        )?;
+
+        let ignore = IgnoreRules::new(&source_dir, &["*.md".to_string(), "*~".to_string()])?;
+        let target_dir = temp_dir.path().join("target");
+        copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::Preserve,
+            &ignore,
+            &CopyOptions::default(),
+        )?;
+
+        assert!(target_dir.join("st.cmd").is_file());
+        assert!(target_dir.join("cfg/motors.cfg").is_file());
+        // negated site default
+        assert!(target_dir.join("README.md").is_file());
+        assert!(!target_dir.join("NOTES.md").exists());
+        assert!(!target_dir.join("st.cmd~").exists());
+        assert!(!target_dir.join("test").exists());
+        assert!(!target_dir.join("build").exists());
+        assert!(!target_dir.join(IGNORE_FILE).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_rules_invalid_pattern() {
+        assert!(IgnoreRules::new(".", &["foo/**[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn copy_files_mode_and_times() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let temp_dir = tempdir()?;
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(&source_dir)?;
+        let script = source_dir.join("helper.sh");
+        fs::write(&script, "#!/bin/sh\n")?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o750))?;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&script)?
+            .set_modified(modified)?;
+
+        let target_dir = temp_dir.path().join("target");
+        copy_recursively(&source_dir, &target_dir, CopyMode::Preserve)?;
+
+        let metadata = fs::metadata(target_dir.join("helper.sh"))?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+        assert_eq!(metadata.modified()?, modified);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_symlinks() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("shared"))?;
+        fs::write(source_dir.join("shared/motor.db"), "motor")?;
+        symlink("shared/motor.db", source_dir.join("motor.db"))?;
+        symlink("shared", source_dir.join("common"))?;
+
+        // follow: copies of the targets
+        let target_dir = temp_dir.path().join("follow");
+        copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::Preserve,
+            &IgnoreRules::none(),
+            &CopyOptions::default(),
+        )?;
+        assert!(!fs::symlink_metadata(target_dir.join("motor.db"))?.is_symlink());
+        assert!(fs::symlink_metadata(target_dir.join("common"))?.is_dir());
+        assert_eq!(
+            fs::read_to_string(target_dir.join("common/motor.db"))?,
+            "motor"
+        );
+
+        // keep: the links themselves
+        let target_dir = temp_dir.path().join("keep");
+        copy_recursively(&source_dir, &target_dir, CopyMode::Preserve)?;
+        assert_eq!(
+            fs::read_link(target_dir.join("motor.db"))?,
+            Path::new("shared/motor.db")
+        );
+        assert_eq!(
+            fs::read_link(target_dir.join("common"))?,
+            Path::new("shared")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn copy_symlinks_flattened() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("db"))?;
+        fs::create_dir_all(source_dir.join("cfg"))?;
+        fs::create_dir_all(source_dir.join("boot"))?;
+        fs::write(source_dir.join("db/x.db"), "x")?;
+        fs::write(source_dir.join("cfg/motor.cfg"), "motor")?;
+        symlink("db/x.db", source_dir.join("alias.db"))?;
+        symlink("../cfg/motor.cfg", source_dir.join("boot/motor.cfg"))?;
+        symlink("motor.cfg", source_dir.join("cfg/default.cfg"))?;
+        let keep = CopyOptions {
+            symlinks: Symlinks::Keep,
+            ..Default::default()
+        };
+
+        // the links follow their targets
+        let target_dir = temp_dir.path().join("flat");
+        copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::preserve_directories(),
+            &IgnoreRules::none(),
+            &keep,
+        )?;
+        assert_eq!(
+            fs::read_link(target_dir.join("alias.db"))?,
+            Path::new("x.db")
+        );
+        assert_eq!(fs::read_to_string(target_dir.join("alias.db"))?, "x");
+        assert_eq!(
+            fs::read_link(target_dir.join("motor.cfg"))?,
+            Path::new("cfg/motor.cfg")
+        );
+        assert_eq!(
+            fs::read_link(target_dir.join("cfg/default.cfg"))?,
+            Path::new("motor.cfg")
+        );
+        // the stage deploys as is
+        let deploy_dir = temp_dir.path().join("deploy");
+        copy_recursively(&target_dir, &deploy_dir, CopyMode::Preserve)?;
+        assert_eq!(fs::read_to_string(deploy_dir.join("alias.db"))?, "x");
+
+        // a flattened directory can't be linked
+        symlink("db", source_dir.join("databases"))?;
+        let error = copy_recursively_with(
+            &source_dir,
+            temp_dir.path().join("refused"),
+            CopyMode::preserve_directories(),
+            &IgnoreRules::none(),
+            &keep,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!temp_dir.path().join("refused").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn copy_symlinks_refused() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let outside = temp_dir.path().join("outside.db");
+        fs::write(&outside, "outside")?;
+        let target_dir = temp_dir.path().join("target");
+        let follow = CopyOptions::default();
+
+        // escaping the source tree
+        let source_dir = temp_dir.path().join("escape");
+        fs::create_dir_all(&source_dir)?;
+        symlink("../outside.db", source_dir.join("motor.db"))?;
+        let error = copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::Preserve,
+            &IgnoreRules::none(),
+            &follow,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // dangling
+        let source_dir = temp_dir.path().join("dangling");
+        fs::create_dir_all(&source_dir)?;
+        symlink("missing.db", source_dir.join("motor.db"))?;
+        let error = copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::Preserve,
+            &IgnoreRules::none(),
+            &follow,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        // cycle
+        let source_dir = temp_dir.path().join("cycle");
+        fs::create_dir_all(source_dir.join("db"))?;
+        symlink("..", source_dir.join("db/parent"))?;
+        assert!(copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::Preserve,
+            &IgnoreRules::none(),
+            &follow,
+        )
+        .is_err());
+
+        // absolute links can't be kept
+        let source_dir = temp_dir.path().join("absolute");
+        fs::create_dir_all(&source_dir)?;
+        fs::write(source_dir.join("shared.db"), "shared")?;
+        symlink(source_dir.join("shared.db"), source_dir.join("motor.db"))?;
+        assert!(copy_recursively(&source_dir, &target_dir, CopyMode::Preserve).is_err());
+        copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::Preserve,
+            &IgnoreRules::none(),
+            &follow,
+        )?;
+        assert_eq!(fs::read_to_string(target_dir.join("motor.db"))?, "shared");
+        assert!(!target_dir.join("shared.db").is_symlink());
+
+        // ignored links aren't checked
+        let source_dir = temp_dir.path().join("ignored");
+        fs::create_dir_all(source_dir.join("scratch"))?;
+        symlink("missing.db", source_dir.join("motor.db"))?;
+        symlink("../../outside.db", source_dir.join("scratch/outside.db"))?;
+        fs::write(source_dir.join(IGNORE_FILE), "motor.db\nscratch/\n")?;
+        let target_dir = temp_dir.path().join("ignored_target");
+        copy_recursively_with(
+            &source_dir,
+            &target_dir,
+            CopyMode::Preserve,
+            &IgnoreRules::new(&source_dir, &[])?,
+            &follow,
+        )?;
+        assert!(!target_dir.join("motor.db").exists());
+        assert!(!target_dir.join("scratch").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn copy_non_utf8_names() -> io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempdir()?;
+        let source_dir = temp_dir.path().join("source");
+        let odd_name = OsStr::from_bytes(b"motor\xff.db");
+        fs::create_dir_all(source_dir.join("db"))?;
+        fs::write(source_dir.join("db").join(odd_name), "motor")?;
+        let target_dir = temp_dir.path().join("target");
+        let copy = |policy: NamePolicy| {
+            copy_recursively_with(
+                &source_dir,
+                &target_dir,
+                CopyMode::preserve_directories(),
+                &IgnoreRules::none(),
+                &CopyOptions {
+                    non_utf8_names: policy,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let error = copy(NamePolicy::Reject).unwrap_err();
+        assert!(error.to_string().contains("not valid UTF-8"));
+        assert!(!target_dir.exists());
+
+        copy(NamePolicy::Warn)?;
+        assert_eq!(fs::read_to_string(target_dir.join(odd_name))?, "motor");
+        copy(NamePolicy::Accept)?;
+        Ok(())
+    }
+
+    #[test]
+    fn layout_settings() {
+        let site = LayoutSettings {
+            layout: None,
+            preserve: Some(vec!["cfg".to_string(), "db".to_string()]),
+            ..Default::default()
+        };
+        let flat = LayoutSettings::default().or(&site);
+        assert_eq!(
+            flat.copy_mode(),
+            CopyMode::FlattenExcept(vec!["cfg".to_string(), "db".to_string()])
+        );
+        assert!(flat.copy_mode().keeps("db"));
+        assert!(!flat.copy_mode().keeps("src"));
+
+        let preserve = LayoutSettings {
+            layout: Some(Layout::Preserve),
+            symlinks: Some(Symlinks::Keep),
+            ..Default::default()
+        };
+        assert_eq!(preserve.or(&site).copy_mode(), CopyMode::Preserve);
+        assert_eq!(preserve.or(&site).copy_options().symlinks, Symlinks::Keep);
+        assert_eq!(flat.copy_options(), CopyOptions::default());
+
+        // nothing configured anywhere
+        assert_eq!(
+            LayoutSettings::default().copy_mode(),
+            CopyMode::preserve_directories()
+        );
+        assert_eq!(
+            CopyMode::preserve_directories()
+                .preserving("env")
+                .preserving("env"),
+            CopyMode::FlattenExcept(vec!["cfg".to_string(), "env".to_string()])
+        );
+    }
+
     #[test]
     fn rm_dir_file() -> io::Result<()> {
         let temp_dir = tempdir()?;
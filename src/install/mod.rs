@@ -2,52 +2,83 @@ use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
 use colored::Colorize;
-use config::Config;
 use glob::glob;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
+use std::io::{BufRead, IsTerminal, Write};
 use std::{env, fs, io};
 
 use crate::{
     ioc::hash_ioc,
+    ioc::ioc_config,
     ioc::IOC,
     log_macros::{cross, exclaim, tick},
-    shellbox, stage,
+    origin::{self, Deployment, Origin, Relation},
+    settings::{Policy, Settings},
+    shellbox,
+    stage::{self, run_dir::RunDir},
 };
 
 // TODO: move to function
 pub fn ioc_install(
     iocs: &Option<Vec<String>>,
-    settings: &Config,
+    settings: &Settings,
     dryrun: &bool,
     nodiff: &bool,
     force: &bool,
+    yes: &bool,
 ) -> io::Result<()> {
     let unique_iocs = check_ioc_list(iocs)?;
-    let stage_root = match settings.get::<String>("filesystem.stage") {
-        Ok(env_key) => match env::var(&env_key) {
-            Ok(val) => val + "/ioc/stage",
-            Err(_) => "/tmp/ioc/stage".to_string(), // Env var not set
-        },
-        Err(_) => "/tmp/ioc/stage".to_string(), // YAML path not found or wrong type
+    // all problems of the batch, before anything is staged
+    ioc_config::validate_batch(&unique_iocs)?;
+    if let Some(profile) = &settings.profile {
+        info!("profile: {}", profile.magenta().bold());
+    }
+    let filesystem = &settings.filesystem;
+    // private to this run, removed at the end, on panic or Ctrl-C
+    let run_dir = match RunDir::create(&filesystem.stage) {
+        Ok(run_dir) => run_dir,
+        Err(e) => {
+            error!("{} failed to create stage directory: {}", cross!(), e);
+            return Err(e);
+        }
     };
 
-    let deploy_root = settings.get::<String>("filesystem.deploy").unwrap();
-    let shellbox_root = settings.get::<String>("filesystem.shellbox").unwrap();
-    let template_dir = settings.get::<String>("app.template_directory").unwrap();
+    let deploy_root = &filesystem.deploy;
+    let shellbox_root = &filesystem.shellbox;
+    let template_dir = &settings.app.template_directory;
+    let site_layout = &settings.app.layout;
+    let site_ignore = &settings.app.ignore;
 
     trace!("configuration ---------------------------");
-    trace!("  stage:    {:?}", stage_root);
+    trace!("  stage:    {:?}", run_dir.path());
     trace!("  deploy:   {:?}", deploy_root);
     trace!("  templates:{:?}", template_dir);
+    trace!("  layout:   {:?}", site_layout);
+    trace!("  ignore:   {:?}", site_ignore);
     trace!("-----------------------------------------");
 
     let ioc_list = IOC::from_list(
         &unique_iocs,
-        &stage_root,
-        &deploy_root,
-        &shellbox_root,
-        &template_dir,
-    );
+        run_dir.path(),
+        deploy_root,
+        shellbox_root,
+        template_dir,
+    )
+    .into_iter()
+    .filter_map(|ioc| {
+        let name = ioc.name.clone();
+        match ioc
+            .with_site_layout(site_layout)
+            .with_site_ignore(site_ignore)
+        {
+            Ok(ioc) => Some(ioc),
+            Err(e) => {
+                error!("{} invalid ignore rules of {}: {}", cross!(), name.red(), e);
+                None
+            }
+        }
+    })
+    .collect::<Vec<IOC>>();
 
     if ioc_list.is_empty() {
         error!("{} ioc list empty - Finishing process", cross!());
@@ -56,9 +87,24 @@ pub fn ioc_install(
 
     trace!("{} ioc list created", tick!());
 
+    let deployment = Deployment::new(force, dryrun).with_profile(&settings.profile);
+    let profile = match &settings.profile {
+        Some(profile) => format!(" [{}]", profile.magenta().bold()),
+        None => String::new(),
+    };
+
     for ioc in &ioc_list {
-        info!("----- {} -----", ioc.name.blue().bold());
+        info!("----- {}{} -----", ioc.name.blue().bold(), profile);
         trace!("{:?}", ioc);
+        if let Err(reason) = check_policy(&ioc.source, &settings.policy) {
+            error!(
+                "{} {} refused by the policy of the profile: {}",
+                cross!(),
+                ioc.name.red().bold(),
+                reason
+            );
+            continue; // skip to next or end
+        }
         // tamper check
         match hash_ioc::check_hash(ioc, force) {
             Ok(_hash) => {}
@@ -74,7 +120,7 @@ pub fn ioc_install(
         }
         // staging
         trace!("staging {}", ioc.name.blue().bold());
-        match stage::stage(ioc) {
+        match stage::stage(ioc, &deployment) {
             Ok(_) => {}
             Err(_) => {
                 error!(
@@ -83,11 +129,14 @@ pub fn ioc_install(
                     ioc.name.red().bold()
                 );
                 ioc_cleanup(ioc)?;
-                remove_dir(Path::new(&stage_root))?;
                 continue; // skip to next or end
             }
         }
 
+        if ioc.destination.exists() {
+            report_changes(ioc);
+        }
+
         if ioc.destination.exists() && !*nodiff {
             // hah, not nodiff, like a proper Bavarian :)
             match ioc.diff_ioc() {
@@ -101,6 +150,26 @@ pub fn ioc_install(
             }
         }
 
+        if !dryrun && settings.policy.confirm && !yes {
+            let question = format!("deploy {}{}?", ioc.name.blue().bold(), profile);
+            let confirmed = match io::stdin().is_terminal() {
+                true => confirm(&question, &mut io::stdin().lock()),
+                false => {
+                    error!(
+                        "{} {} requires confirmation, use --yes when not interactive",
+                        cross!(),
+                        ioc.name.red().bold()
+                    );
+                    false
+                }
+            };
+            if !confirmed {
+                warn!("{} {} not deployed", exclaim!(), ioc.name.yellow());
+                ioc_cleanup(ioc)?;
+                continue; // skip to next or end
+            }
+        }
+
         // deployment
         if !dryrun {
             // actual deployment run
@@ -125,7 +194,6 @@ pub fn ioc_install(
             }
 
             ioc_cleanup(ioc)?;
-            remove_dir(Path::new(&stage_root))?;
         } else {
             // dryrun
             info!("{} was chosen, no deployment", "--dryrun".yellow());
@@ -136,7 +204,56 @@ pub fn ioc_install(
     Ok(())
 }
 
-fn check_ioc_list(list: &Option<Vec<String>>) -> io::Result<Vec<String>> {
+/// report how the source relates to the commit recorded in the deployed ORIGIN
+fn report_changes(ioc: &IOC) {
+    let deployed = match Origin::read_origin_file(&ioc.destination) {
+        Ok(origin) => origin,
+        Err(e) => {
+            debug!("{} no ORIGIN for {}: {}", exclaim!(), ioc.name.blue(), e);
+            return;
+        }
+    };
+    match deployed.compare_with_source(&ioc.source) {
+        Relation::UpToDate => info!("{} deployed commit is the source HEAD", tick!()),
+        Relation::Ahead(commits) => {
+            let log: Vec<String> = commits.iter().map(|c| format!("\n\t{c}")).collect();
+            info!(
+                "deploying {} new commit{}: {}",
+                commits.len(),
+                if commits.len() == 1 { "" } else { "s" },
+                log.concat()
+            );
+        }
+        Relation::Behind(commits) => {
+            let log: Vec<String> = commits.iter().map(|c| format!("\n\t{c}")).collect();
+            warn!(
+                "{} source is {} commit(s) behind the deployment, reverting: {}",
+                exclaim!(),
+                commits.len(),
+                log.concat()
+            );
+        }
+        Relation::Diverged { ahead, behind } => {
+            let ahead_log: Vec<String> = ahead.iter().map(|c| format!("\n\t+ {c}")).collect();
+            let behind_log: Vec<String> = behind.iter().map(|c| format!("\n\t- {c}")).collect();
+            warn!(
+                "{} source and deployment diverged, deploying {} new and dropping {} deployed commit(s): {}{}",
+                exclaim!(),
+                ahead.len(),
+                behind.len(),
+                ahead_log.concat(),
+                behind_log.concat()
+            );
+        }
+        Relation::Unknown(reason) => debug!(
+            "{} can't compare source with deployment: {}",
+            exclaim!(),
+            reason
+        ),
+    }
+}
+
+pub(crate) fn check_ioc_list(list: &Option<Vec<String>>) -> io::Result<Vec<String>> {
     let ioc_list = match list {
         Some(l) => filter_duplicates(l.clone())?,
         None => {
@@ -182,6 +299,30 @@ fn filter_duplicates(paths: Vec<String>) -> io::Result<Vec<String>> {
     Ok(result)
 }
 
+/// Deploying from `source` is permitted by `policy`, or the reason it isn't.
+fn check_policy(source: &Path, policy: &Policy) -> Result<(), String> {
+    if policy.require_tag {
+        if origin::head_tag(source).is_none() {
+            return Err("the source is not a tagged commit".to_string());
+        }
+        if Origin::new(source).is_dirty() {
+            return Err("the source has uncommitted changes".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// ask `question` and read the answer from `input`, only yes confirms
+fn confirm(question: &str, input: &mut impl BufRead) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    match input.read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
 fn ioc_cleanup(ioc: &IOC) -> io::Result<()> {
     trace!("cleaning up staging directory for {}", &ioc.name);
     remove_dir(&ioc.stage)?;
@@ -209,6 +350,48 @@ mod tests {
         assert!(Path::new(check_ioc_list(&None).unwrap().first().unwrap()).is_dir());
     }
 
+    #[test]
+    fn test_confirm() {
+        assert!(confirm("deploy?", &mut "y\n".as_bytes()));
+        assert!(confirm("deploy?", &mut " YES \n".as_bytes()));
+        assert!(!confirm("deploy?", &mut "\n".as_bytes()));
+        assert!(!confirm("deploy?", &mut "no\n".as_bytes()));
+        assert!(!confirm("deploy?", &mut "".as_bytes()));
+    }
+
+    #[test]
+    fn test_check_policy() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let repo = git2::Repository::init(temp_dir.path()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        fs::write(temp_dir.path().join("startup.iocsh"), "")?;
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("startup.iocsh")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let commit = repo
+            .commit(Some("HEAD"), &signature, &signature, "first", &tree, &[])
+            .unwrap();
+
+        let policy = Policy {
+            require_tag: true,
+            ..Default::default()
+        };
+        assert!(check_policy(temp_dir.path(), &Policy::default()).is_ok());
+        let error = check_policy(temp_dir.path(), &policy).unwrap_err();
+        assert!(error.contains("not a tagged commit"));
+
+        let commit = repo.find_object(commit, None).unwrap();
+        repo.tag("v1.0", &commit, &signature, "release", false)
+            .unwrap();
+        assert!(check_policy(temp_dir.path(), &policy).is_ok());
+
+        fs::write(temp_dir.path().join("startup.iocsh"), "changed")?;
+        let error = check_policy(temp_dir.path(), &policy).unwrap_err();
+        assert!(error.contains("uncommitted changes"));
+        Ok(())
+    }
+
     #[test]
     fn test_filter_duplicates() -> io::Result<()> {
         let input = vec!["foo", "bar"];
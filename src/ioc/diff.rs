@@ -3,6 +3,8 @@ use std::{fs, io, path::Path};
 use diffy::{create_patch, PatchFormatter};
 use log::info;
 
+use crate::file_system::CopyMode;
+
 fn get_patch<P>(original: P, modified: P) -> io::Result<String>
 where
     P: AsRef<Path>,
@@ -31,41 +33,35 @@ fn filter_comments(input: &str) -> String {
     filtered
 }
 
-pub fn diff_recursively<P>(source: P, destination: P) -> io::Result<()>
+/// diff the staged files with the deployed ones, directories are mapped like `copy_mode` does
+pub fn diff_recursively<P>(source: P, destination: P, copy_mode: &CopyMode) -> io::Result<()>
 where
     P: AsRef<Path>,
 {
     for entry in fs::read_dir(source)? {
         let entry = entry?;
-        if entry.file_name().into_string().unwrap().starts_with('.') {
+        let file_name = entry.file_name();
+        if file_name.as_encoded_bytes().starts_with(b".") {
             continue;
         }
-        if !destination.as_ref().join(entry.file_name()).exists() {
+        let deployed = destination.as_ref().join(&file_name);
+        if !deployed.exists() {
             continue;
         }
 
         let filetype = entry.file_type()?;
         if filetype.is_dir() {
-            if let Ok(dir_name) = entry.file_name().into_string() {
-                if dir_name == "cfg" {
-                    diff_recursively(entry.path(), destination.as_ref().join(dir_name))?;
-                } else {
-                    diff_recursively(entry.path(), destination.as_ref().to_path_buf())?;
-                }
+            if copy_mode.keeps(&file_name) {
+                diff_recursively(entry.path(), deployed, copy_mode)?;
+            } else {
+                diff_recursively(entry.path(), destination.as_ref().to_path_buf(), copy_mode)?;
             }
         } else {
-            let patch = get_patch(destination.as_ref().join(entry.file_name()), entry.path())?;
+            let patch = get_patch(&deployed, &entry.path())?;
             if patch.lines().count() > 3 {
                 info!("===========================================================");
-                info!("--- original: {}", entry.path().to_str().unwrap());
-                info!(
-                    "+++ modified: {}",
-                    destination
-                        .as_ref()
-                        .join(entry.file_name())
-                        .to_str()
-                        .unwrap()
-                );
+                info!("--- original: {}", entry.path().display());
+                info!("+++ modified: {}", deployed.display());
                 info!("DIFF:\n{}", patch);
                 info!("===========================================================");
             }
@@ -104,28 +100,47 @@ mod tests {
         let source_cfg = source.join("cfg");
         let dest = temp_dir.path().join("dest");
         let dest_cfg = dest.join("cfg");
+        let mode = CopyMode::preserve_directories();
 
         fs::create_dir_all(&source_cfg)?;
         fs::create_dir_all(&dest_cfg)?;
         // empty dirs -> Ok
-        assert!(diff_recursively(&source, &dest).is_ok());
+        assert!(diff_recursively(&source, &dest, &mode).is_ok());
 
         fs::write(source_cfg.join(".hidden_file1"), ".hidden_file1")?;
         // skip hidden files -> Ok
-        assert!(diff_recursively(&source, &dest).is_ok());
+        assert!(diff_recursively(&source, &dest, &mode).is_ok());
 
         fs::write(source_cfg.join("new_file"), "this is new")?;
         // skip new files -> Ok
-        assert!(diff_recursively(&source, &dest).is_ok());
+        assert!(diff_recursively(&source, &dest, &mode).is_ok());
 
         fs::write(dest.join("file1.txt"), "file1_dest")?;
         // files root available -> Ok
-        assert!(diff_recursively(&source, &dest).is_ok());
+        assert!(diff_recursively(&source, &dest, &mode).is_ok());
 
         fs::write(source_cfg.join("cfg1.txt"), "config_file1")?;
         fs::write(dest_cfg.join("cfg1.txt"), "config_file1_dest")?;
         // files "cfg"" available -> Ok
-        assert!(diff_recursively(source, dest).is_ok());
+        assert!(diff_recursively(source, dest, &mode).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_non_utf8_names() -> io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("source");
+        let dest = temp_dir.path().join("dest");
+        let odd_name = std::ffi::OsStr::from_bytes(b"motor\xff.db");
+        fs::create_dir_all(source.join("db"))?;
+        fs::create_dir_all(&dest)?;
+        fs::write(source.join("db").join(odd_name), "motor")?;
+        fs::write(dest.join(odd_name), "motor dest")?;
+
+        let mode = CopyMode::preserve_directories();
+        assert!(diff_recursively(&source, &dest, &mode).is_ok());
         Ok(())
     }
 }
@@ -1,27 +1,66 @@
-use crate::log_macros::{cross, exclaim};
+use crate::file_system::{Layout, NamePolicy, Symlinks};
+use crate::ioc::permissions::PermissionPolicy;
+use crate::ioc::IocType;
+use crate::log_macros::{cross, exclaim, tick};
 use colored::Colorize;
-use config::{Config, ConfigError, File};
-use log::{error, warn};
-use serde_derive::Deserialize;
+use config::{Config, ConfigError, File, Map, Value, ValueKind};
+use log::{debug, error, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
 
-#[derive(Debug, Deserialize, Clone, Default)]
+/// default shellbox command
+pub const DEFAULT_COMMAND: &str = "iocsh";
+/// default arguments of the shellbox command
+pub const DEFAULT_COMMAND_ARGS: &str = "startup.iocsh";
+/// file extensions of IOC configs, `config.<extension>` in the IOC directory
+pub const IOC_CONFIG_EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+/// hostnames are shorter than this
+const MAX_HOSTNAME_LEN: usize = 16;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 #[allow(unused)]
 pub struct IocConfig {
     pub port: u16,
     pub host: String,
     pub user: String,
+    /// group of the deployed files, the primary group of `user` if not set
+    pub group: Option<String>,
     pub base_dir: Option<String>,
     pub command: String,
     pub command_args: String,
     // pub name: Option<String>,
     pub procserv_opts: String,
     pub python_based: bool,
+    /// script started by the launcher of Python IOCs, relative to the deployed IOC
+    pub entry_point: Option<String>,
+    /// site templates shared by a group of IOCs, `groups/<template_group>/` of the template
+    /// directory
+    pub template_group: Option<String>,
+    /// IOC type, detected from the source if not set
+    #[serde(rename = "type")]
+    pub ioc_type: Option<IocType>,
+    /// layout of the deployed IOC, the site default if not set
+    pub layout: Option<Layout>,
+    /// directories kept intact by the flat layout, the site default if not set
+    pub preserve: Option<Vec<String>>,
+    /// copy symbolic links or what they point to, the site default if not set
+    pub symlinks: Option<Symlinks>,
+    /// handling of file names which aren't valid UTF-8, the site default if not set
+    pub non_utf8_names: Option<NamePolicy>,
+    /// permissions of the deployed files
+    #[serde(default)]
+    pub permissions: PermissionPolicy,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Settings {
     pub ioc: IocConfig,
+    /// free-form variables for the templates, `[vars]` table of the IOC config
+    #[serde(default)]
+    pub vars: BTreeMap<String, tera::Value>,
 }
 
 impl Settings {
@@ -33,9 +72,9 @@ impl Settings {
             .unwrap()
             .set_default("ioc.user", "control2")
             .unwrap()
-            .set_default("ioc.command", "iocsh")
+            .set_default("ioc.command", DEFAULT_COMMAND)
             .unwrap()
-            .set_default("ioc.command_args", "startup.iocsh")
+            .set_default("ioc.command_args", DEFAULT_COMMAND_ARGS)
             .unwrap()
             .set_default("ioc.procserv_opts", "")
             .unwrap()
@@ -52,7 +91,7 @@ impl Settings {
 
 fn check_hostname(conf: &mut Config) -> Result<String, ConfigError> {
     let mut hostname = conf.get_string("ioc.host").unwrap();
-    if hostname.len() >= 16 {
+    if hostname.len() >= MAX_HOSTNAME_LEN {
         error!("{} hostname has {} characters.", cross!(), hostname.len());
         let e = ConfigError::Message("Hostname too long.".to_string());
         return Err(e);
@@ -76,3 +115,458 @@ fn rebuild(key: String, value: String, config: Config) -> Result<Config, ConfigE
         .build()?;
     Ok(s)
 }
+
+/// the config file of the IOC in `dir`, if any
+pub fn config_file(dir: &Path) -> Option<PathBuf> {
+    IOC_CONFIG_EXTENSIONS
+        .iter()
+        .map(|extension| dir.join("config").with_extension(extension))
+        .find(|file| file.is_file())
+}
+
+/// expected value of a key of the IOC config
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    String,
+    /// string of at most the given number of characters, not empty
+    Name(usize),
+    Bool,
+    /// integer in the inclusive range
+    Integer(i64, i64),
+    /// one of the listed strings
+    OneOf(&'static [&'static str]),
+    /// array of strings
+    Strings,
+    Table(&'static [Key]),
+    /// table with any keys and values
+    FreeForm,
+}
+
+/// a key of the IOC config schema
+#[derive(Debug)]
+struct Key {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn key(name: &'static str, kind: Kind) -> Key {
+    Key {
+        name,
+        kind,
+        required: false,
+    }
+}
+
+const fn required(name: &'static str, kind: Kind) -> Key {
+    Key {
+        name,
+        kind,
+        required: true,
+    }
+}
+
+/// `[ioc.permissions]`, see `PermissionPolicy`
+const PERMISSIONS_SCHEMA: &[Key] = &[
+    key("read_only", Kind::Bool),
+    key("writable", Kind::Strings),
+    key("executable", Kind::Strings),
+];
+
+/// `[ioc]`, see `IocConfig`
+const IOC_SCHEMA: &[Key] = &[
+    required("host", Kind::Name(MAX_HOSTNAME_LEN - 1)),
+    required("port", Kind::Integer(1, u16::MAX as i64)),
+    key("user", Kind::Name(usize::MAX)),
+    key("group", Kind::Name(usize::MAX)),
+    key("base_dir", Kind::String),
+    key("command", Kind::Name(usize::MAX)),
+    key("command_args", Kind::String),
+    key("procserv_opts", Kind::String),
+    key("python_based", Kind::Bool),
+    key("entry_point", Kind::Name(usize::MAX)),
+    key("template_group", Kind::Name(usize::MAX)),
+    key("type", Kind::OneOf(&["compiled", "python"])),
+    key("layout", Kind::OneOf(&["flat", "preserve"])),
+    key("preserve", Kind::Strings),
+    key("symlinks", Kind::OneOf(&["follow", "keep"])),
+    key("non_utf8_names", Kind::OneOf(&["reject", "warn", "accept"])),
+    key("permissions", Kind::Table(PERMISSIONS_SCHEMA)),
+];
+
+/// the IOC config file
+const SCHEMA: &[Key] = &[
+    required("ioc", Kind::Table(IOC_SCHEMA)),
+    key("vars", Kind::FreeForm),
+];
+
+/// problem of an IOC config, found by `validate`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// no config file, or it can't be parsed
+    File(String),
+    /// a key not in the schema, with the closest known key
+    Unknown {
+        key: String,
+        suggestion: Option<String>,
+    },
+    /// a required key is not set
+    Missing(String),
+    /// the value has the wrong type
+    Type {
+        key: String,
+        expected: String,
+        found: String,
+    },
+    /// the value has the right type, but isn't allowed
+    Range { key: String, reason: String },
+}
+
+impl Problem {
+    /// the key the problem belongs to, array items without their index
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Problem::File(_) => None,
+            Problem::Unknown { key, .. }
+            | Problem::Missing(key)
+            | Problem::Type { key, .. }
+            | Problem::Range { key, .. } => key.split('[').next(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::File(reason) => write!(f, "{}", reason),
+            Problem::Unknown {
+                key,
+                suggestion: Some(suggestion),
+            } => write!(f, "unknown key '{}', did you mean '{}'?", key, suggestion),
+            Problem::Unknown { key, .. } => write!(f, "unknown key '{}'", key),
+            Problem::Missing(key) => write!(f, "missing required key '{}'", key),
+            Problem::Type {
+                key,
+                expected,
+                found,
+            } => write!(f, "'{}' must be {}, found {}", key, expected, found),
+            Problem::Range { key, reason } => write!(f, "'{}' {}", key, reason),
+        }
+    }
+}
+
+/// Validate the IOC config in `dir` against the schema, all problems at once.
+pub fn validate(dir: &Path) -> Vec<Problem> {
+    let Some(file) = config_file(dir) else {
+        return vec![Problem::File(format!(
+            "no config file, expected config.{{{}}}",
+            IOC_CONFIG_EXTENSIONS.join(",")
+        ))];
+    };
+    let table = match Config::builder()
+        .add_source(File::from(file.as_path()))
+        .build()
+        .and_then(|config| config.try_deserialize::<Map<String, Value>>())
+    {
+        Ok(table) => table,
+        Err(e) => return vec![Problem::File(e.to_string())],
+    };
+    let mut problems = Vec::new();
+    check_table("", &table, SCHEMA, &mut problems);
+    problems
+}
+
+/// Validate the IOC configs of a batch before anything is staged. The problems of all IOCs are
+/// logged, and fail the batch. Missing IOC directories are left to `IOC::new`.
+pub fn validate_batch(iocs: &[String]) -> io::Result<()> {
+    let mut invalid = 0;
+    for source in iocs.iter().map(Path::new).filter(|s| s.is_dir()) {
+        let problems = validate(source);
+        if problems.is_empty() {
+            debug!("{} valid IOC config of {:?}", tick!(), source);
+            continue;
+        }
+        invalid += 1;
+        error!("{} invalid IOC config of {:?}:", cross!(), source);
+        problems.iter().for_each(|p| error!("  - {p}"));
+    }
+    match invalid {
+        0 => Ok(()),
+        n => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} of {} IOC config(s) invalid, nothing staged",
+                n,
+                iocs.len()
+            ),
+        )),
+    }
+}
+
+fn check_table(
+    prefix: &str,
+    table: &Map<String, Value>,
+    schema: &[Key],
+    problems: &mut Vec<Problem>,
+) {
+    let dotted = |name: &str| match prefix {
+        "" => name.to_string(),
+        _ => format!("{}.{}", prefix, name),
+    };
+    let mut names: Vec<&String> = table.keys().collect();
+    names.sort();
+    for name in names {
+        if !schema.iter().any(|k| k.name == name) {
+            let known: Vec<&str> = schema.iter().map(|k| k.name).collect();
+            problems.push(Problem::Unknown {
+                key: dotted(name),
+                suggestion: suggest(name, &known).map(dotted),
+            });
+        }
+    }
+    for key in schema {
+        match table.get(key.name) {
+            Some(value) => check_value(&dotted(key.name), value, key.kind, problems),
+            None if key.required => problems.push(Problem::Missing(dotted(key.name))),
+            None => {}
+        }
+    }
+}
+
+fn check_value(key: &str, value: &Value, kind: Kind, problems: &mut Vec<Problem>) {
+    let mismatch = |expected: &str| Problem::Type {
+        key: key.to_string(),
+        expected: expected.to_string(),
+        found: describe(value),
+    };
+    let range = |reason: String| Problem::Range {
+        key: key.to_string(),
+        reason,
+    };
+    match (kind, &value.kind) {
+        (Kind::String, ValueKind::String(_)) => {}
+        (Kind::String, _) => problems.push(mismatch("a string")),
+        (Kind::Name(max), ValueKind::String(name)) => {
+            if name.trim().is_empty() {
+                problems.push(range("must not be empty".to_string()));
+            } else if name.chars().count() > max {
+                problems.push(range(format!(
+                    "has {} characters, at most {} are allowed",
+                    name.chars().count(),
+                    max
+                )));
+            }
+        }
+        (Kind::Name(_), _) => problems.push(mismatch("a string")),
+        (Kind::Bool, ValueKind::Boolean(_)) => {}
+        (Kind::Bool, _) => problems.push(mismatch("true or false")),
+        (Kind::Integer(min, max), _) => match integer(value) {
+            // quoted numbers used to be the documented format
+            Some(n)
+                if matches!(value.kind, ValueKind::String(_))
+                    && n >= min as i128
+                    && n <= max as i128 =>
+            {
+                warn!(
+                    "{} '{}' is the string \"{}\", quoted numbers are deprecated, write it without quotes",
+                    exclaim!(),
+                    key,
+                    n
+                )
+            }
+            Some(n) if n < min as i128 || n > max as i128 => problems.push(range(format!(
+                "is {}, it must be between {} and {}",
+                n, min, max
+            ))),
+            Some(_) => {}
+            None => problems.push(mismatch("an integer")),
+        },
+        (Kind::OneOf(allowed), ValueKind::String(s)) => {
+            if !allowed.contains(&s.as_str()) {
+                let hint = match suggest(s, allowed) {
+                    Some(suggestion) => format!(", did you mean \"{}\"?", suggestion),
+                    None => String::new(),
+                };
+                problems.push(range(format!(
+                    "is \"{}\", it must be one of {}{}",
+                    s,
+                    allowed.join(", "),
+                    hint
+                )));
+            }
+        }
+        (Kind::OneOf(allowed), _) => {
+            problems.push(mismatch(&format!("one of {}", allowed.join(", "))))
+        }
+        (Kind::Strings, ValueKind::Array(items)) => {
+            for (n, item) in items.iter().enumerate() {
+                if !matches!(item.kind, ValueKind::String(_)) {
+                    problems.push(Problem::Type {
+                        key: format!("{}[{}]", key, n),
+                        expected: "a string".to_string(),
+                        found: describe(item),
+                    });
+                }
+            }
+        }
+        (Kind::Strings, _) => problems.push(mismatch("an array of strings")),
+        (Kind::Table(schema), ValueKind::Table(table)) => check_table(key, table, schema, problems),
+        (Kind::FreeForm, ValueKind::Table(_)) => {}
+        (Kind::Table(_) | Kind::FreeForm, _) => problems.push(mismatch("a table")),
+    }
+}
+
+fn integer(value: &Value) -> Option<i128> {
+    match value.kind {
+        ValueKind::I64(n) => Some(n as i128),
+        ValueKind::I128(n) => Some(n),
+        ValueKind::U64(n) => Some(n as i128),
+        ValueKind::U128(n) => i128::try_from(n).ok(),
+        ValueKind::String(ref s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// the type and value, for messages
+fn describe(value: &Value) -> String {
+    match &value.kind {
+        ValueKind::Nil => "nothing".to_string(),
+        ValueKind::Boolean(b) => format!("boolean {}", b),
+        ValueKind::I64(_) | ValueKind::I128(_) | ValueKind::U64(_) | ValueKind::U128(_) => {
+            format!("integer {}", value)
+        }
+        ValueKind::Float(f) => format!("float {}", f),
+        ValueKind::String(s) => format!("string \"{}\"", s),
+        ValueKind::Table(_) => "a table".to_string(),
+        ValueKind::Array(_) => "an array".to_string(),
+    }
+}
+
+/// the known name closest to `name`, if it is close enough to be a typo
+fn suggest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|k| (strsim::damerau_levenshtein(name, k), *k))
+        .filter(|(distance, k)| *distance <= 2.max(k.len() / 3))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, k)| k)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::tempdir;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn check(config: &str) -> Vec<Problem> {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("config.toml"), config).unwrap();
+        validate(temp_dir.path())
+    }
+
+    #[test]
+    fn valid_config() {
+        // with the deprecated quoted port
+        assert_eq!(validate(Path::new("./tests/UTEST_IOC01")), vec![]);
+        assert_eq!(
+            check("[ioc]\nhost = \"iochost\"\nport = \"20000\"\n"),
+            vec![]
+        );
+        let config = "[ioc]\nhost = \"iochost\"\nport = 20000\nlayout = \"preserve\"\n\
+                      preserve = [\"cfg\"]\n\n[ioc.permissions]\nread_only = false\n\n\
+                      [vars]\nanything = { goes = [1, \"two\"] }\n";
+        assert_eq!(check(config), vec![]);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let problems = check(
+            "[ioc]\nhots = \"iochost\"\nport = 20000\nprocserv_opt = \"-q\"\nnetwork = \"\"\n\n\
+             [ioc.permissions]\nread_onyl = true\n\n[varz]\n",
+        );
+        let unknown = |key: &str, suggestion: Option<&str>| Problem::Unknown {
+            key: key.to_string(),
+            suggestion: suggestion.map(str::to_string),
+        };
+        assert_eq!(
+            problems,
+            vec![
+                unknown("varz", Some("vars")),
+                unknown("ioc.hots", Some("ioc.host")),
+                unknown("ioc.network", None),
+                unknown("ioc.procserv_opt", Some("ioc.procserv_opts")),
+                Problem::Missing("ioc.host".to_string()),
+                unknown(
+                    "ioc.permissions.read_onyl",
+                    Some("ioc.permissions.read_only")
+                ),
+            ]
+        );
+        assert_eq!(
+            problems[1].to_string(),
+            "unknown key 'ioc.hots', did you mean 'ioc.host'?"
+        );
+    }
+
+    #[test]
+    fn types_and_ranges() {
+        let problems = check(
+            "[ioc]\nhost = \"a_very_long_hostname\"\nport = \"12345a\"\npython_based = \"yes\"\n\
+             layout = \"flatt\"\npreserve = [\"cfg\", 1]\npermissions = \"none\"\n",
+        );
+        let messages: Vec<String> = problems.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "'ioc.host' has 20 characters, at most 15 are allowed",
+                "'ioc.port' must be an integer, found string \"12345a\"",
+                "'ioc.python_based' must be true or false, found string \"yes\"",
+                "'ioc.layout' is \"flatt\", it must be one of flat, preserve, did you mean \"flat\"?",
+                "'ioc.preserve[1]' must be a string, found integer 1",
+                "'ioc.permissions' must be a table, found string \"none\"",
+            ]
+        );
+
+        let problems = check("[ioc]\nhost = \"\"\nport = \"0\"\n");
+        assert_eq!(
+            problems.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec![
+                "'ioc.host' must not be empty",
+                "'ioc.port' is 0, it must be between 1 and 65535",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_and_broken_files() {
+        let temp_dir = tempdir().unwrap();
+        assert!(matches!(validate(temp_dir.path())[..], [Problem::File(_)]));
+        fs::write(temp_dir.path().join("config.toml"), "[ioc\n").unwrap();
+        assert!(matches!(validate(temp_dir.path())[..], [Problem::File(_)]));
+        fs::write(temp_dir.path().join("config.toml"), "").unwrap();
+        assert_eq!(
+            validate(temp_dir.path()),
+            vec![Problem::Missing("ioc".to_string())]
+        );
+    }
+
+    #[test]
+    fn batch() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let broken = temp_dir.path().join("UTEST_IOC02");
+        fs::create_dir_all(&broken)?;
+        fs::write(broken.join("config.toml"), "[ioc]\nhots = \"iochost\"\n")?;
+        let valid = "./tests/UTEST_IOC01".to_string();
+        let missing = temp_dir.path().join("MISSING").display().to_string();
+
+        validate_batch(&[valid.clone(), missing])?;
+        let error = validate_batch(&[broken.display().to_string(), valid]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "1 of 2 IOC config(s) invalid, nothing staged"
+        );
+        Ok(())
+    }
+}
@@ -7,22 +7,38 @@ use log::{debug, error, trace, warn};
 
 use crate::log_macros::exclaim;
 use crate::{
-    file_system::{self, CopyMode},
+    file_system::{self, CopyMode, CopyOptions, IgnoreRules, LayoutSettings, IGNORE_FILE},
     log_macros::{cross, tick},
 };
+use serde_derive::{Deserialize, Serialize};
 
 mod diff;
 pub mod hash_ioc;
 
 pub(crate) mod ioc_config;
+pub mod permissions;
 pub mod python_ioc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum IocType {
     Python,
     Compiled,
 }
 
+impl IocType {
+    /// all supported IOC types
+    pub const ALL: [IocType; 2] = [IocType::Compiled, IocType::Python];
+
+    /// site templates needed to stage an IOC of this type
+    pub fn required_templates(&self) -> &'static [&'static str] {
+        match self {
+            IocType::Compiled => &["startup.tera"],
+            IocType::Python => &[python_ioc::PYTHON_STARTUP_TEMPLATE],
+        }
+    }
+}
+
 /// IOC structure
 #[derive(Debug, Clone)]
 pub struct IOC {
@@ -46,6 +62,12 @@ pub struct IOC {
     pub templates: PathBuf,
     /// ioc type (python or c/c++ based)
     pub ioc_type: IocType,
+    /// layout of the staged and deployed IOC
+    pub layout: CopyMode,
+    /// options for copying the source to the stage
+    pub copy_options: CopyOptions,
+    /// files of the source not staged
+    pub ignore: IgnoreRules,
 }
 
 /// IOC structure implementation
@@ -60,17 +82,21 @@ impl IOC {
         shellbox_root: impl AsRef<Path>,
         template_root: impl AsRef<Path>,
     ) -> Result<IOC, &'static str> {
-        let name = source
-            .as_ref()
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
+        let name = match ioc_name(source.as_ref()) {
+            Some(name) => name,
+            None => {
+                error!(
+                    "{} IOC directory name is not valid UTF-8: {}",
+                    cross!(),
+                    source.as_ref().display()
+                );
+                return Err("Invalid name of IOC directory.");
+            }
+        };
         let stage = stage_root.as_ref().join(&name);
         let data = destination_root.as_ref().join("data").join(&name);
         let hash_file = data.join("hash");
-        let config = match ioc_config::Settings::build(
+        let config: ioc_config::Settings = match ioc_config::Settings::build(
             source
                 .as_ref()
                 .to_path_buf()
@@ -85,9 +111,17 @@ impl IOC {
             }
         };
 
+        let ignore = match IgnoreRules::new(&source, &[]) {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("{} invalid {}: {}", cross!(), IGNORE_FILE, e);
+                return Err("Invalid ignore file of IOC.");
+            }
+        };
+
         // check source directory exists
         match source.as_ref().is_dir() {
-            true => match check_ioc_type(&source) {
+            true => match check_ioc_type(&source, &config.ioc) {
                 None => {
                     warn!("{} IOC source not found.", exclaim!(),);
                     Err("Could not find source of IOC.")
@@ -103,6 +137,13 @@ impl IOC {
                         hash_file,
                         destination,
                         shellbox_root: shellbox_root.as_ref().to_path_buf(),
+                        ignore: ignore.clone(),
+                        copy_options: layout_settings(&config.ioc).copy_options(),
+                        layout: copy_mode(
+                            &config.ioc,
+                            &LayoutSettings::default(),
+                            IocType::Compiled,
+                        ),
                         config,
                         templates: template_root.as_ref().to_path_buf(),
                         ioc_type: IocType::Compiled,
@@ -120,6 +161,9 @@ impl IOC {
                         hash_file,
                         destination,
                         shellbox_root: shellbox_root.as_ref().to_path_buf(),
+                        ignore: ignore.clone(),
+                        copy_options: layout_settings(&config.ioc).copy_options(),
+                        layout: copy_mode(&config.ioc, &LayoutSettings::default(), IocType::Python),
                         config,
                         templates: template_root.as_ref().to_path_buf(),
                         ioc_type: IocType::Python,
@@ -137,6 +181,25 @@ impl IOC {
         }
     }
 
+    /// apply the site default layout, unless the IOC config sets its own
+    pub fn with_site_layout(mut self, site: &LayoutSettings) -> Self {
+        self.layout = copy_mode(&self.config.ioc, site, self.ioc_type);
+        self.copy_options = layout_settings(&self.config.ioc).or(site).copy_options();
+        self
+    }
+
+    /// stage into `dir` instead of the directory below the stage root
+    pub fn with_stage(mut self, dir: impl AsRef<Path>) -> Self {
+        self.stage = dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// apply the site default ignore patterns, followed by the `.iocignore` of the IOC
+    pub fn with_site_ignore(mut self, patterns: &[String]) -> io::Result<Self> {
+        self.ignore = IgnoreRules::new(&self.source, patterns)?;
+        Ok(self)
+    }
+
     pub fn from_list(
         list: &[String],
         stage_root: impl AsRef<Path>,
@@ -147,7 +210,10 @@ impl IOC {
         debug!("collecting iocs ...");
         list.iter()
             .filter_map(|source| {
-                let curr_ioc = source.rsplit_once('/').unwrap().1;
+                let curr_ioc = Path::new(source)
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
 
                 trace!("source dir: {:?}", curr_ioc);
                 // TODO: `match` this to create pleasing Error log
@@ -176,7 +242,7 @@ impl IOC {
 
     pub fn diff_ioc(&self) -> io::Result<()> {
         trace!("diff for {}", self.name.blue());
-        diff::diff_recursively(&self.stage, &self.destination)?;
+        diff::diff_recursively(&self.stage, &self.destination, &self.layout)?;
         Ok(())
     }
 
@@ -186,6 +252,7 @@ impl IOC {
             file_system::remove_dir_contents(&self.destination)?; // prep deploy directory
             trace!("{} removed {:?}", tick!(), &self.destination);
         }
+        // links of the stage were checked while staging
         file_system::copy_recursively(&self.stage, &self.destination, CopyMode::Preserve)?;
         trace!(
             "{} copied {:?} -> {:?}",
@@ -194,6 +261,15 @@ impl IOC {
             &self.destination.as_path()
         );
 
+        if let Err(e) = permissions::apply(self) {
+            warn!(
+                "{} failed to set ownership and permissions of {}: {}",
+                exclaim!(),
+                self.name.yellow(),
+                e
+            );
+        }
+
         hash_ioc::hash_ioc(self)?;
         debug!(
             "{} deployment of {:?} to {:?} complete.",
@@ -205,31 +281,98 @@ impl IOC {
     }
 }
 
-// check if IOC is compiled or python
-fn check_ioc_type(source_dir: impl AsRef<Path>) -> Option<IocType> {
+/// name of the IOC, the name of its source directory, which may contain dots, e.g. `IOC.v2`
+fn ioc_name(source: &Path) -> Option<String> {
+    let name = match source.file_name() {
+        Some(name) => name.to_owned(),
+        // e.g. `.` or `..`
+        None => source.canonicalize().ok()?.file_name()?.to_owned(),
+    };
+    name.into_string().ok()
+}
+
+/// layout settings of the IOC config
+fn layout_settings(config: &ioc_config::IocConfig) -> LayoutSettings {
+    LayoutSettings {
+        layout: config.layout,
+        preserve: config.preserve.clone(),
+        symlinks: config.symlinks,
+        non_utf8_names: config.non_utf8_names,
+    }
+}
+
+/// Copy mode of an IOC, the layout settings of the IOC config fall back to the site defaults.
+/// The Conda environment of Python IOCs is always preserved.
+fn copy_mode(config: &ioc_config::IocConfig, site: &LayoutSettings, ioc_type: IocType) -> CopyMode {
+    let copy_mode = layout_settings(config).or(site).copy_mode();
+    match ioc_type {
+        IocType::Compiled => copy_mode,
+        IocType::Python => copy_mode.preserving(python_ioc::CONDA_ENV_DIR),
+    }
+}
+
+/// Determine the IOC type. The `type` of the IOC config takes precedence, the deprecated
+/// `python_based` flag comes second. Otherwise the type is detected from the source: a
+/// `startup.iocsh` indicates a compiled IOC, any Python file a Python IOC.
+pub(crate) fn check_ioc_type(
+    source_dir: impl AsRef<Path>,
+    config: &ioc_config::IocConfig,
+) -> Option<IocType> {
     let source_path = source_dir.as_ref();
-    let start_script = source_path.join("startup.iocsh");
-
-    if start_script.is_file() {
-        trace!("{} Found compiled IOC: {}", tick!(), source_path.display());
-        Some(IocType::Compiled)
-    } else if python_ioc::is_python_ioc(source_path) {
-        trace!("{} Found Python IOC: {}", tick!(), source_path.display());
-        Some(IocType::Python)
-    } else {
+    if let Some(ioc_type) = config.ioc_type {
         trace!(
-            "{} No IOC type detected: {}",
+            "{} Configured {:?} IOC: {}",
             tick!(),
+            ioc_type,
             source_path.display()
         );
-        None
+        return Some(ioc_type);
+    }
+    if config.python_based {
+        warn!(
+            "{} `python_based` is deprecated, use `type = \"python\"` in the IOC config",
+            exclaim!()
+        );
+        return Some(IocType::Python);
+    }
+
+    let start_script = source_path.join("startup.iocsh").is_file();
+    let python_files = python_ioc::is_python_ioc(source_path);
+    match (start_script, python_files) {
+        (true, true) => {
+            warn!(
+                "{} found startup.iocsh and Python files in {}, assuming a compiled IOC. \
+                 Set `type` in the IOC config to silence this warning.",
+                exclaim!(),
+                source_path.display()
+            );
+            Some(IocType::Compiled)
+        }
+        (true, false) => {
+            trace!("{} Found compiled IOC: {}", tick!(), source_path.display());
+            Some(IocType::Compiled)
+        }
+        (false, true) => {
+            trace!("{} Found Python IOC: {}", tick!(), source_path.display());
+            Some(IocType::Python)
+        }
+        (false, false) => {
+            trace!(
+                "{} No IOC type detected: {}",
+                tick!(),
+                source_path.display()
+            );
+            None
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use serial_test::serial;
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::origin::Deployment;
     use crate::settings::Settings;
     use crate::stage;
     use tempfile::tempdir;
@@ -261,6 +404,122 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_check_ioc_type() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path();
+        let mut config = ioc_config::IocConfig::default();
+
+        // nothing to detect
+        assert_eq!(check_ioc_type(source, &config), None);
+
+        std::fs::write(source.join("helper.py"), "")?;
+        assert_eq!(check_ioc_type(source, &config), Some(IocType::Python));
+
+        // ambiguous, startup.iocsh wins
+        std::fs::write(source.join("startup.iocsh"), "")?;
+        assert_eq!(check_ioc_type(source, &config), Some(IocType::Compiled));
+
+        config.python_based = true;
+        assert_eq!(check_ioc_type(source, &config), Some(IocType::Python));
+
+        // configured type takes precedence
+        config.ioc_type = Some(IocType::Compiled);
+        assert_eq!(check_ioc_type(source, &config), Some(IocType::Compiled));
+        config.ioc_type = Some(IocType::Python);
+        assert_eq!(check_ioc_type(source, &config), Some(IocType::Python));
+        Ok(())
+    }
+
+    #[test]
+    fn test_configured_ioc_type() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("PTEST_IOC01");
+        std::fs::create_dir_all(&source)?;
+        std::fs::write(source.join("startup.iocsh"), "")?;
+        std::fs::write(
+            source.join("config.toml"),
+            "[ioc]\nhost = \"iochost\"\nport = 20000\ntype = \"python\"\n",
+        )?;
+
+        let test_ioc = crate::test_utils::new_test_ioc(&source)?;
+        assert_eq!(test_ioc.ioc_type, IocType::Python);
+        assert!(test_ioc.destination.ends_with("python/PTEST_IOC01"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ioc_layout() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC02");
+        std::fs::create_dir_all(&source)?;
+        std::fs::write(source.join("startup.iocsh"), "")?;
+        let site = LayoutSettings {
+            layout: None,
+            preserve: Some(vec!["cfg".to_string(), "db".to_string()]),
+            ..Default::default()
+        };
+
+        // built-in default, then the site default
+        let test_ioc = crate::test_utils::new_test_ioc(&source)?;
+        assert_eq!(test_ioc.layout, CopyMode::preserve_directories());
+        let test_ioc = test_ioc.with_site_layout(&site);
+        assert!(test_ioc.layout.keeps("db"));
+        assert!(!test_ioc.layout.keeps("src"));
+
+        // the IOC config takes precedence
+        std::fs::write(
+            source.join("config.toml"),
+            "[ioc]\nhost = \"iochost\"\nport = 20000\npreserve = [\"autosave\"]\n",
+        )?;
+        let test_ioc = crate::test_utils::new_test_ioc(&source)?.with_site_layout(&site);
+        assert_eq!(
+            test_ioc.layout,
+            CopyMode::FlattenExcept(vec!["autosave".to_string()])
+        );
+        std::fs::write(
+            source.join("config.toml"),
+            "[ioc]\nhost = \"iochost\"\nport = 20000\nlayout = \"preserve\"\n",
+        )?;
+        let test_ioc = crate::test_utils::new_test_ioc(&source)?.with_site_layout(&site);
+        assert_eq!(test_ioc.layout, CopyMode::Preserve);
+
+        // the Conda environment is always kept
+        std::fs::write(
+            source.join("config.toml"),
+            "[ioc]\nhost = \"iochost\"\nport = 20000\ntype = \"python\"\n",
+        )?;
+        let test_ioc = crate::test_utils::new_test_ioc(&source)?.with_site_layout(&site);
+        assert_eq!(
+            test_ioc.layout,
+            CopyMode::FlattenExcept(vec![
+                "cfg".to_string(),
+                "db".to_string(),
+                python_ioc::CONDA_ENV_DIR.to_string()
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ioc_name() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC.v2");
+        std::fs::create_dir_all(&source)?;
+        std::fs::write(source.join("startup.iocsh"), "")?;
+
+        assert_eq!(ioc_name(&source).unwrap(), "UTEST_IOC.v2");
+        assert_eq!(ioc_name(&source.join(".")).unwrap(), "UTEST_IOC.v2");
+        let test_ioc = crate::test_utils::new_test_ioc(&source)?;
+        assert_eq!(test_ioc.name, "UTEST_IOC.v2");
+        assert!(test_ioc.destination.ends_with("UTEST_IOC.v2"));
+
+        use std::os::unix::ffi::OsStrExt;
+        let odd_name = std::ffi::OsStr::from_bytes(b"UTEST_IOC\xff");
+        assert_eq!(ioc_name(&temp_dir.path().join(odd_name)), None);
+        Ok(())
+    }
+
     #[test]
     fn test_from_list_success() -> io::Result<()> {
         let temp_dir = tempdir()?;
@@ -284,9 +543,12 @@ mod tests {
     }
 
     #[test]
+    #[serial]
     fn test_ioc_deploy_success() -> io::Result<()> {
-        let settings = Settings::build("tests/config/test_deploy.toml").unwrap();
-        let template_dir = settings.get::<String>("app.template_directory").unwrap();
+        let template_dir = Settings::load("tests/config/test_deploy.toml", None, &[])
+            .unwrap()
+            .app
+            .template_directory;
 
         let temp_dir = tempdir()?;
         let stage_dir = temp_dir.path().join("stage");
@@ -301,7 +563,7 @@ mod tests {
             template_dir,
         )
         .unwrap();
-        assert!(stage::stage(&test_ioc).is_ok());
+        assert!(stage::stage(&test_ioc, &Deployment::new(&false, &false)).is_ok());
         assert!(test_ioc.deploy().is_ok());
         Ok(())
     }
@@ -0,0 +1,206 @@
+use std::os::unix::fs::{lchown, PermissionsExt};
+use std::path::Path;
+use std::{fs, io};
+
+use colored::Colorize;
+use glob::Pattern;
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::ioc::IOC;
+use crate::log_macros::{exclaim, tick};
+
+/// permissions of deployed files, `[ioc.permissions]` of the IOC config
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PermissionPolicy {
+    /// deployed files are read-only, except in the writable directories
+    pub read_only: bool,
+    /// top-level directories the IOC user may write to
+    pub writable: Vec<String>,
+    /// file name patterns made executable, in addition to files executable in the source
+    pub executable: Vec<String>,
+}
+
+impl Default for PermissionPolicy {
+    fn default() -> Self {
+        Self {
+            read_only: true,
+            writable: vec!["cfg".to_string()],
+            executable: vec!["*.sh".to_string()],
+        }
+    }
+}
+
+impl PermissionPolicy {
+    /// mode of a deployed file or directory, `path` relative to the deployed IOC
+    pub fn mode(&self, path: &Path, is_dir: bool, current: u32) -> u32 {
+        let writable = !self.read_only
+            || path
+                .components()
+                .next()
+                .is_some_and(|c| self.writable.iter().any(|w| c.as_os_str() == w.as_str()));
+        if is_dir {
+            return if writable { 0o775 } else { 0o755 };
+        }
+        let executable = current & 0o111 != 0
+            || path.file_name().is_some_and(|name| {
+                self.executable
+                    .iter()
+                    .filter_map(|p| Pattern::new(p).ok())
+                    .any(|p| p.matches(&name.to_string_lossy()))
+            });
+        match (writable, executable) {
+            (true, true) => 0o775,
+            (true, false) => 0o664,
+            (false, true) => 0o555,
+            (false, false) => 0o444,
+        }
+    }
+}
+
+/// Hand the deployed IOC to the IOC user and apply the permission policy. Without the
+/// privileges to change the ownership, nothing is changed and a warning is logged.
+pub fn apply(ioc: &IOC) -> io::Result<()> {
+    let user_name = &ioc.config.ioc.user;
+    let user = users::get_user_by_name(user_name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user {}", user_name),
+        )
+    })?;
+    let gid = match &ioc.config.ioc.group {
+        Some(group_name) => users::get_group_by_name(group_name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unknown group {}", group_name),
+                )
+            })?
+            .gid(),
+        None => user.primary_group_id(),
+    };
+
+    match lchown(&ioc.destination, Some(user.uid()), Some(gid)) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(
+                "{} not permitted to hand {} to {}, ownership and permissions unchanged",
+                exclaim!(),
+                ioc.name.yellow(),
+                user_name.yellow()
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    let policy = &ioc.config.ioc.permissions;
+    apply_recursively(&ioc.destination, &ioc.destination, user.uid(), gid, policy)?;
+    fs::set_permissions(
+        &ioc.destination,
+        fs::Permissions::from_mode(policy.mode(Path::new(""), true, 0)),
+    )?;
+    debug!(
+        "{} {} owned by {}:{}",
+        tick!(),
+        ioc.destination.display(),
+        user.uid(),
+        gid
+    );
+    Ok(())
+}
+
+fn apply_recursively(
+    root: &Path,
+    dir: &Path,
+    uid: u32,
+    gid: u32,
+    policy: &PermissionPolicy,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        lchown(&path, Some(uid), Some(gid))?;
+        // the mode of a link is the one of its target
+        if file_type.is_symlink() {
+            continue;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let current = entry.metadata()?.permissions().mode();
+        let mode = policy.mode(relative, file_type.is_dir(), current);
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        if file_type.is_dir() {
+            apply_recursively(root, &path, uid, gid, policy)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::origin::get_user_name;
+    use tempfile::tempdir;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn policy_modes() {
+        let policy = PermissionPolicy::default();
+        assert_eq!(policy.mode(Path::new("st.cmd"), false, 0o644), 0o444);
+        assert_eq!(policy.mode(Path::new("helper"), false, 0o750), 0o555);
+        assert_eq!(policy.mode(Path::new("start_ioc.sh"), false, 0o644), 0o555);
+        assert_eq!(policy.mode(Path::new("db"), true, 0o700), 0o755);
+        assert_eq!(policy.mode(Path::new("cfg"), true, 0o755), 0o775);
+        assert_eq!(
+            policy.mode(Path::new("cfg/motors.cfg"), false, 0o644),
+            0o664
+        );
+        assert_eq!(policy.mode(Path::new("cfg/reset.sh"), false, 0o644), 0o775);
+
+        let policy = PermissionPolicy {
+            read_only: false,
+            ..Default::default()
+        };
+        assert_eq!(policy.mode(Path::new("st.cmd"), false, 0o644), 0o664);
+    }
+
+    #[test]
+    fn apply_policy() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC01");
+        fs::create_dir_all(&source)?;
+        fs::write(source.join("startup.iocsh"), "")?;
+        fs::write(
+            source.join("config.toml"),
+            format!(
+                "[ioc]\nhost = \"iochost\"\nport = 20000\nuser = \"{}\"\n",
+                get_user_name().to_string_lossy()
+            ),
+        )?;
+        let test_ioc = IOC::new(
+            &source,
+            temp_dir.path().join("stage"),
+            temp_dir.path().join("dest"),
+            temp_dir.path().join("shellbox"),
+            temp_dir.path().join("templates"),
+        )
+        .unwrap();
+        fs::create_dir_all(test_ioc.destination.join("cfg"))?;
+        fs::write(test_ioc.destination.join("startup.iocsh"), "")?;
+        fs::write(test_ioc.destination.join("cfg/motors.cfg"), "")?;
+
+        // handing the files to ourselves is always permitted
+        apply(&test_ioc)?;
+        let mode = |p: &str| -> io::Result<u32> {
+            Ok(fs::metadata(test_ioc.destination.join(p))?
+                .permissions()
+                .mode()
+                & 0o777)
+        };
+        assert_eq!(mode("startup.iocsh")?, 0o444);
+        assert_eq!(mode("cfg")?, 0o775);
+        assert_eq!(mode("cfg/motors.cfg")?, 0o664);
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
 
 use glob::glob;
+use tree_sitter::{Node, Parser};
+
+use crate::ioc::ioc_config::{IocConfig, DEFAULT_COMMAND, DEFAULT_COMMAND_ARGS};
+use crate::ioc::IOC;
 
 // logging
 use crate::log_macros::{exclaim, tick};
@@ -13,6 +18,25 @@ pub const CONDA_ENV_DIR: &str = "env";
 /// Customized Conda environment yaml description.
 pub const CONDA_ENV_CFG: &str = "conda_config.yaml";
 
+/// Launcher script of Python IOCs, rendered from `PYTHON_STARTUP_TEMPLATE`
+pub const PYTHON_LAUNCHER: &str = "start_ioc.sh";
+
+/// Site template for the launcher script
+pub const PYTHON_STARTUP_TEMPLATE: &str = "python_startup.tera";
+
+/// Script started by the launcher, unless `entry_point` is set in the IOC config
+pub const DEFAULT_ENTRY_POINT: &str = "main.py";
+
+/// Entry point of a Python IOC, relative to the deployed IOC
+pub fn entry_point(config: &IocConfig) -> &str {
+    config.entry_point.as_deref().unwrap_or(DEFAULT_ENTRY_POINT)
+}
+
+/// The IOC is started by the launcher, unless `command` or `command_args` are configured
+pub fn uses_launcher(config: &IocConfig) -> bool {
+    config.command == DEFAULT_COMMAND && config.command_args == DEFAULT_COMMAND_ARGS
+}
+
 /// Check if the directory contains Python files, indicating it's a Python IOC
 pub fn is_python_ioc(dir: impl AsRef<Path>) -> bool {
     let pattern = format!("{}/**/*.py", dir.as_ref().display());
@@ -31,3 +55,285 @@ pub fn is_python_ioc(dir: impl AsRef<Path>) -> bool {
         false
     }
 }
+
+/// Files of the Conda environment larger than this are not searched for absolute paths
+const MAX_SCANNED_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Number of files of the Conda environment reported, before the rest is summarized
+const MAX_ENV_PROBLEMS: usize = 10;
+
+/// Problem found in a file of a Python IOC definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// file relative to the IOC
+    pub file: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.file.display(), self.message)
+    }
+}
+
+/// Validate a staged Python IOC:
+///
+/// - the entry point exists, if it is configured or started by the launcher
+/// - `conda_config.yaml` parses and names an environment
+/// - the `env` directory does not point at the checkout of the IOC definition
+/// - all Python files outside of `env` are syntactically valid
+pub fn validate(ioc: &IOC) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let problem = |file: &Path, message: String| Problem {
+        file: file.strip_prefix(&ioc.stage).unwrap_or(file).to_path_buf(),
+        message,
+    };
+
+    let config = &ioc.config.ioc;
+    if config.entry_point.is_some() || uses_launcher(config) {
+        let entry_point = ioc.stage.join(entry_point(config));
+        if !entry_point.is_file() {
+            problems.push(problem(&entry_point, "entry point not found".to_string()));
+        }
+    }
+
+    let conda_config = ioc.stage.join(CONDA_ENV_CFG);
+    if conda_config.is_file() {
+        if let Err(message) = check_conda_config(&conda_config) {
+            problems.push(problem(&conda_config, message));
+        }
+    }
+
+    let env_dir = ioc.stage.join(CONDA_ENV_DIR);
+    if env_dir.is_dir() {
+        let checkout = ioc.source.canonicalize().unwrap_or(ioc.source.clone());
+        let mut env_problems = Vec::new();
+        for file in files(&env_dir).unwrap_or_default() {
+            if let Some(message) = check_env_file(&file, &checkout) {
+                env_problems.push(problem(&file, message));
+            }
+        }
+        let more = env_problems.len().saturating_sub(MAX_ENV_PROBLEMS);
+        env_problems.truncate(MAX_ENV_PROBLEMS);
+        problems.extend(env_problems);
+        if more > 0 {
+            problems.push(problem(
+                &env_dir,
+                format!("{} more files point at {}", more, checkout.display()),
+            ));
+        }
+    }
+
+    let mut parser = Parser::new();
+    parser
+        .set_language(&tree_sitter_python::LANGUAGE.into())
+        .expect("incompatible tree-sitter Python grammar");
+    for file in files(&ioc.stage).unwrap_or_default() {
+        if file.starts_with(&env_dir) || file.extension().is_none_or(|ext| ext != "py") {
+            continue;
+        }
+        if let Err(message) = check_syntax(&mut parser, &file) {
+            problems.push(problem(&file, message));
+        }
+    }
+
+    trace!(
+        "{} validated Python IOC {}, {} problem(s)",
+        tick!(),
+        ioc.name,
+        problems.len()
+    );
+    problems
+}
+
+/// all files below `dir`, sorted
+fn files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        if entry.file_type()?.is_dir() {
+            found.extend(files(&entry.path())?);
+        } else {
+            found.push(entry.path());
+        }
+    }
+    Ok(found)
+}
+
+/// the Conda config needs to be valid YAML and name the environment
+fn check_conda_config(path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let config: serde_yaml::Value =
+        serde_yaml::from_str(&content).map_err(|e| format!("invalid YAML: {}", e))?;
+    match config.get("name").and_then(serde_yaml::Value::as_str) {
+        Some(name) if !name.trim().is_empty() => Ok(()),
+        _ => Err("does not name an environment, `name` is missing".to_string()),
+    }
+}
+
+/// shebangs and absolute paths in the environment must not point at the checkout
+fn check_env_file(path: &Path, checkout: &Path) -> Option<String> {
+    let checkout = checkout.as_os_str().as_encoded_bytes();
+    let size = fs::symlink_metadata(path).ok()?.len();
+    if size > MAX_SCANNED_FILE_SIZE {
+        return None;
+    }
+    let content = fs::read(path).ok()?;
+    let first_line = content.split(|&b| b == b'\n').next().unwrap_or_default();
+    if first_line.starts_with(b"#!") && contains(first_line, checkout) {
+        return Some(format!(
+            "shebang points at the checkout: {}",
+            String::from_utf8_lossy(first_line)
+        ));
+    }
+    if contains(&content, checkout) {
+        return Some(format!(
+            "contains the absolute path of the checkout {}",
+            String::from_utf8_lossy(checkout)
+        ));
+    }
+    None
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    !needle.is_empty() && haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// parse a Python file and report the first syntax error
+fn check_syntax(parser: &mut Parser, path: &Path) -> Result<(), String> {
+    let code = fs::read(path).map_err(|e| e.to_string())?;
+    if std::str::from_utf8(&code).is_err() {
+        return Err("invalid UTF-8".to_string());
+    }
+    let tree = parser
+        .parse(&code, None)
+        .ok_or_else(|| "failed to parse".to_string())?;
+    match first_error(tree.root_node()) {
+        None => Ok(()),
+        Some(node) => {
+            let position = node.start_position();
+            let what = match node.is_missing() {
+                true => format!("missing `{}`", node.kind()),
+                false => "syntax error".to_string(),
+            };
+            Err(format!(
+                "{} at line {}, column {}",
+                what,
+                position.row + 1,
+                position.column + 1
+            ))
+        }
+    }
+}
+
+fn first_error(node: Node) -> Option<Node> {
+    if node.is_error() || node.is_missing() {
+        return Some(node);
+    }
+    if !node.has_error() {
+        return None;
+    }
+    let mut cursor = node.walk();
+    let children: Vec<Node> = node.children(&mut cursor).collect();
+    children.into_iter().find_map(first_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    /// Python IOC with an empty stage
+    fn python_test_ioc(source: &Path) -> io::Result<IOC> {
+        fs::create_dir_all(source)?;
+        fs::write(
+            source.join("config.toml"),
+            "[ioc]\nhost = \"iochost\"\nport = 20000\ntype = \"python\"\n",
+        )?;
+        let root = source.parent().unwrap();
+        let ioc = IOC::new(
+            source,
+            root.join("stage"),
+            root.join("dest"),
+            root.join("shellbox"),
+            root.join("templates"),
+        )
+        .expect("failed to build IOC!");
+        fs::create_dir_all(ioc.stage.join(CONDA_ENV_DIR).join("bin"))?;
+        Ok(ioc)
+    }
+
+    #[test]
+    fn validate_success() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let ioc = python_test_ioc(&temp_dir.path().join("PTEST_IOC01"))?;
+        fs::write(ioc.stage.join("main.py"), "def main():\n    print('ioc')\n")?;
+        fs::write(ioc.stage.join(CONDA_ENV_CFG), "name: ptest\n")?;
+        fs::write(
+            ioc.stage.join("env/bin/pip"),
+            "#!/usr/bin/env python\nimport pip\n",
+        )?;
+        // syntax errors in the environment are not our business
+        fs::write(ioc.stage.join("env/legacy.py"), "print 'python 2'\n")?;
+
+        assert_eq!(validate(&ioc), vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_custom_command() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let mut ioc = python_test_ioc(&temp_dir.path().join("PTEST_IOC01"))?;
+        ioc.config.ioc.command = "python".to_string();
+        ioc.config.ioc.command_args = "-m ptest".to_string();
+
+        // not started by the launcher, no default entry point needed
+        assert_eq!(validate(&ioc), vec![]);
+
+        // unless one is configured
+        ioc.config.ioc.entry_point = Some("ioc.py".to_string());
+        let files: Vec<PathBuf> = validate(&ioc).into_iter().map(|p| p.file).collect();
+        assert_eq!(files, vec![PathBuf::from("ioc.py")]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_problems() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("PTEST_IOC01");
+        let mut ioc = python_test_ioc(&source)?;
+        ioc.config.ioc.entry_point = Some("ioc.py".to_string());
+        let checkout = source.canonicalize()?;
+
+        fs::write(ioc.stage.join("main.py"), "def main(:\n    pass\n")?;
+        fs::write(ioc.stage.join("helper.py"), "x = (1, 2\n")?;
+        fs::write(ioc.stage.join(CONDA_ENV_CFG), "dependencies:\n  - python\n")?;
+        fs::write(
+            ioc.stage.join("env/bin/pip"),
+            format!("#!{}/env/bin/python\n", checkout.display()),
+        )?;
+        fs::write(
+            ioc.stage.join("env/conda-meta"),
+            format!("prefix: {}/env\n", checkout.display()),
+        )?;
+
+        let problems = validate(&ioc);
+        let files: Vec<&Path> = problems.iter().map(|p| p.file.as_path()).collect();
+        assert_eq!(
+            files,
+            vec![
+                Path::new("ioc.py"),
+                Path::new(CONDA_ENV_CFG),
+                Path::new("env/bin/pip"),
+                Path::new("env/conda-meta"),
+                Path::new("helper.py"),
+                Path::new("main.py"),
+            ]
+        );
+        assert!(problems[2].message.starts_with("shebang"));
+        assert!(problems[5].message.contains("line 1"));
+        Ok(())
+    }
+}
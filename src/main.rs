@@ -1,6 +1,5 @@
 use std::io::{self, Error};
 
-use std::env;
 use std::path::Path;
 use std::process::exit;
 // for CLI
@@ -8,12 +7,13 @@ use clap::{CommandFactory, Parser};
 
 // logging
 use colored::Colorize;
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, trace, warn};
 use simple_logger::SimpleLogger;
 
 // my mods
 pub mod cli;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, ConfigCommands};
+mod doctor;
 mod install;
 pub mod ioc;
 pub mod log_macros;
@@ -25,9 +25,11 @@ mod file_system;
 mod metadata;
 mod origin;
 pub mod shellbox;
+mod show;
 
 use crate::log_macros::{cross, tick};
 use metadata::PackageData;
+use origin::{Deployment, Origin};
 
 #[cfg(test)]
 mod test_utils;
@@ -58,14 +60,34 @@ fn main() -> io::Result<()> {
         .init()
         .unwrap();
 
-    let settings = Settings::build(&config_file).unwrap();
-    /* TODO: This still very sub-optimal. In this early stage, a std::process::exit would do.
-        Maybe the tera instance could be created here already?
-    */
-    match Settings::verify(&settings) {
-        Ok(_) => trace!("{} verified {}", tick!(), config_file),
-        Err(e) => {
-            error!("{} config file verification failed with\n {e}!", cross!());
+    let profile = settings::selected_profile(&cli.profile);
+    // before the settings are verified, to report all problems
+    if let Some(Commands::Doctor) = &cli.command {
+        debug!("command: <{}>", "doctor".yellow());
+        let checks = doctor::doctor(&config_file, profile.as_deref(), &cli.set);
+        exit(doctor::report(&checks));
+    }
+    if let Some(Commands::Config(args)) = &cli.command {
+        let ConfigCommands::Show(show_args) = &args.command;
+        debug!("command: <{}>", "config show".yellow());
+        let problems = show::config_show(
+            &config_file,
+            profile.as_deref(),
+            &cli.profile,
+            &cli.set,
+            &show_args.ioc,
+            show_args.format,
+        )?;
+        exit(i32::from(problems > 0));
+    }
+    let settings = match Settings::load(&config_file, profile.as_deref(), &cli.set) {
+        Ok(settings) => {
+            trace!("{} verified {}", tick!(), config_file);
+            settings
+        }
+        Err(errors) => {
+            error!("{} config file verification failed with:", cross!());
+            errors.iter().for_each(|e| error!("  - {e}"));
             exit(1)
         }
     };
@@ -80,6 +102,12 @@ fn main() -> io::Result<()> {
             debug!("dryrun: {}", args.dryrun);
             debug!("no diff: {}", args.nodiff);
             debug!("force:  {}", args.force);
+            let problems = settings.check_directories();
+            if !problems.is_empty() && !args.dryrun {
+                error!("{} site directories not usable:", cross!());
+                problems.iter().for_each(|e| error!("  - {e}"));
+                exit(1)
+            }
             // worker
             install::ioc_install(
                 &args.iocs,
@@ -87,28 +115,20 @@ fn main() -> io::Result<()> {
                 &args.dryrun,
                 &args.nodiff,
                 &args.force,
+                &args.yes,
             )?;
             Ok(())
         }
         Some(Commands::Uninstall(args)) => {
             let source = Path::new(&args.ioc);
-            let stage_root = match settings.get::<String>("filesystem.stage") {
-                Ok(env_key) => match env::var(&env_key) {
-                    Ok(val) => val + "/ioc/stage",
-                    Err(_) => "/tmp/ioc/stage".to_string(), // Env var not set
-                },
-                Err(_) => "/tmp/ioc/stage".to_string(), // YAML path not found or wrong type
-            };
-
-            let deploy_root = settings.get::<String>("filesystem.deploy").unwrap();
-            let shellbox_root = settings.get::<String>("filesystem.shellbox").unwrap();
-            let template_dir = settings.get::<String>("app.template_directory").unwrap();
+            let filesystem = &settings.filesystem;
+            let (deploy_root, shellbox_root) = (&filesystem.deploy, &filesystem.shellbox);
             let ioc_struct = match ioc::IOC::new(
                 source,
-                &stage_root,
-                &deploy_root,
-                &shellbox_root,
-                &template_dir,
+                &filesystem.stage,
+                deploy_root,
+                shellbox_root,
+                &settings.app.template_directory,
             ) {
                 Ok(ioc) => ioc,
                 Err(e) => {
@@ -135,34 +155,57 @@ fn main() -> io::Result<()> {
             Ok(())
         }
         Some(Commands::Stage(args)) => {
-            info!("----- {} -----", args.ioc.blue().bold());
+            debug!("command: <{}>", "stage".yellow());
+            stage::ioc_stage(&args.iocs, &settings, &args.path, &args.tree, &args.clean)
+        }
+        Some(Commands::Render(args)) => {
             let source = Path::new(&args.ioc);
-            let stage_root = match settings.get::<String>("filesystem.stage") {
-                Ok(env_key) => match env::var(&env_key) {
-                    Ok(val) => val + "/ioc/stage",
-                    Err(_) => "/tmp/ioc/stage".to_string(), // Env var not set
-                },
-                Err(_) => "/tmp/ioc/stage".to_string(), // YAML path not found or wrong type
+            let filesystem = &settings.filesystem;
+            let template_dir = match &args.templates {
+                Some(templates) => templates.to_owned(),
+                None => settings.app.template_directory.clone(),
             };
-
-            let deploy_root = settings.get::<String>("filesystem.deploy").unwrap();
-            let shellbox_root = settings.get::<String>("filesystem.shellbox").unwrap();
-            let template_dir = settings.get::<String>("app.template_directory").unwrap();
-            match ioc::IOC::new(
+            let problems = ioc::ioc_config::validate(source);
+            if !problems.is_empty() {
+                error!("{} invalid IOC config of {:?}:", cross!(), source);
+                problems.iter().for_each(|p| error!("  - {p}"));
+                exit(1);
+            }
+            let ioc = match ioc::IOC::new(
                 source,
-                &stage_root,
-                &deploy_root,
-                &shellbox_root,
+                &filesystem.stage,
+                &filesystem.deploy,
+                &filesystem.shellbox,
                 &template_dir,
             ) {
-                Ok(ioc) => stage::stage(&ioc)?,
+                Ok(ioc) => ioc,
                 Err(e) => {
                     error!("{} failed to build IOC with: {}", cross!(), e.red());
-                    // return Err(Error::new(std::io::ErrorKind::InvalidData, "invalid list"));
+                    exit(1);
                 }
             };
+            let deployment = Deployment::new(&false, &true).with_profile(&settings.profile);
+            let origin = Origin::from_ioc(&ioc, &deployment);
+            if args.context {
+                println!("{}", stage::render::context_json(&ioc, &origin));
+            }
+            match stage::render::render_template(&ioc, &template_dir, &args.template, &origin) {
+                Ok(rendered) => print!("{rendered}"),
+                Err(e) => {
+                    error!(
+                        "{} failed to render {} with: {}",
+                        cross!(),
+                        args.template.red().bold(),
+                        e
+                    );
+                    exit(1);
+                }
+            }
             Ok(())
         }
+        Some(Commands::Config(_)) | Some(Commands::Doctor) => {
+            unreachable!("handled before loading the settings")
+        }
         None => {
             let error_msg = "no active command, check --help for more information.";
             error!("{} {}", cross!(), error_msg);
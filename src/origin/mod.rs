@@ -1,14 +1,27 @@
-use git2::{Repository, RepositoryState};
+use git2::{Oid, Repository, RepositoryState, StatusOptions};
+use log::{debug, trace};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
-use chrono::DateTime;
+use chrono::{DateTime, Local};
+
+use crate::ioc::{ioc_config::IocConfig, IOC};
+use crate::metadata::PackageData;
+
+/// version of the ORIGIN file layout, bump whenever fields are added, renamed or removed.
+pub const ORIGIN_SCHEMA_VERSION: u32 = 4;
+
+/// name of the file the origin information is written to
+pub const ORIGIN_FILE: &str = "ORIGIN";
 
 /// struct for origin information
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Origin {
+    schema: u32,
     directory: PathBuf,
     remote: String,
     branch: String,
@@ -18,6 +31,67 @@ pub struct Origin {
     tag: String,
     author: String,
     state: String,
+    dirty: bool,
+    deployment: Deployment,
+    ioc: Option<IocConfig>,
+}
+
+/// who, where, when and how an IOC was deployed
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Deployment {
+    user: String,
+    host: String,
+    tool: String,
+    version: String,
+    date: String,
+    force: bool,
+    dryrun: bool,
+    /// profile of the site configuration
+    profile: Option<String>,
+}
+
+impl Deployment {
+    /// new Deployment for the current user and machine
+    ///
+    /// # Arguments
+    ///
+    /// * `force` - install was forced (`--force`).
+    /// * `dryrun` - install was a dryrun (`--dryrun`).
+    pub fn new(force: &bool, dryrun: &bool) -> Self {
+        let metadata = PackageData::new();
+        Deployment {
+            user: get_user_name().to_string_lossy().into_owned(),
+            host: get_host_name().to_string_lossy().into_owned(),
+            tool: metadata.get_name().to_owned(),
+            version: metadata.get_version().to_owned(),
+            date: Local::now().to_rfc3339(),
+            force: *force,
+            dryrun: *dryrun,
+            profile: None,
+        }
+    }
+
+    /// this Deployment with the settings of `profile`
+    pub fn with_profile(self, profile: &Option<String>) -> Self {
+        Deployment {
+            profile: profile.clone(),
+            ..self
+        }
+    }
+}
+
+/// name of the user running the tool, falls back to `$USER`
+pub(crate) fn get_user_name() -> OsString {
+    match users::get_current_username() {
+        Some(uname) => uname,
+        None => std::env::var_os("USER").unwrap_or("unknown".into()),
+    }
+}
+
+/// name of the machine the tool is running on
+pub(crate) fn get_host_name() -> OsString {
+    hostname::get().unwrap_or("unknown".into())
 }
 
 impl Origin {
@@ -32,19 +106,22 @@ impl Origin {
     ///
     /// Origin
     pub fn new<P: AsRef<Path>>(dir: P) -> Self {
-        let mut origin: Origin = Default::default();
-        origin.directory = dir.as_ref().canonicalize().unwrap_or_default();
+        let mut origin = Origin {
+            schema: ORIGIN_SCHEMA_VERSION,
+            directory: dir.as_ref().canonicalize().unwrap_or_default(),
+            ..Default::default()
+        };
 
         if let Ok(repo) = Repository::discover(dir) {
-            // Print the branch name
+            // branch name
             if let Ok(head) = repo.head() {
                 if let Some(name) = head.shorthand() {
                     origin.branch = name.to_owned();
-                    println!("Branch: {}", origin.branch);
+                    debug!("Branch: {}", origin.branch);
                 }
             }
 
-            // Print the last commit details
+            // last commit details
             if let Ok(reference) = repo.head() {
                 if let Some(oid) = reference.target() {
                     if let Ok(commit) = repo.find_commit(oid) {
@@ -54,36 +131,62 @@ impl Origin {
                         origin.time = DateTime::from_timestamp(commit.time().seconds(), 0)
                             .unwrap_or_default()
                             .to_string();
-                        println!("Author: {}", origin.author);
-                        println!("Message: {}", origin.message);
-                        println!("Commit: {}", origin.commit);
-                        println!("Time: {}", origin.time);
+                        debug!("Author: {}", origin.author);
+                        debug!("Message: {}", origin.message);
+                        debug!("Commit: {}", origin.commit);
+                        debug!("Time: {}", origin.time);
                     }
                 }
             }
 
-            // Print the remote URL
+            // remote URL
             if let Ok(remote) = repo.find_remote("origin") {
                 if let Some(url) = remote.url() {
                     origin.remote = url.to_owned();
+                    debug!("Remote: {}", origin.remote);
                 }
             }
 
             // Check if the current commit is tagged
             if let Ok(tag_name) = repo.describe(git2::DescribeOptions::new().describe_tags()) {
                 origin.tag = tag_name.format(None).unwrap_or_default();
-                // println!("Tagged Version: {}", tag_name.format(None).unwrap());
+                debug!("Tag: {}", origin.tag);
             }
 
-            // Print the repository state (e.g., if it's clean, has uncommitted changes, etc.)
+            // uncommitted or untracked changes within the directory
+            origin.dirty = is_dirty(&repo, &origin.directory);
+            debug!("Dirty: {}", origin.dirty);
+
+            // repository state (e.g., if it's clean, has uncommitted changes, etc.)
             match repo.state() {
                 RepositoryState::Clean => origin.state = "clean".to_owned(),
                 _ => origin.state = format!("{:?}", repo.state()),
             }
+        } else {
+            trace!("{:?} is not a git repository", origin.directory);
         }
         origin
     }
 
+    /// new Origin for an IOC, including the deployment metadata and the resolved IOC config.
+    ///
+    /// # Arguments
+    ///
+    /// * `ioc` - the IOC to be deployed.
+    /// * `deployment` - deployment metadata.
+    pub fn from_ioc(ioc: &IOC, deployment: &Deployment) -> Self {
+        Origin {
+            deployment: deployment.clone(),
+            ioc: Some(ioc.config.ioc.clone()),
+            ..Origin::new(&ioc.source)
+        }
+    }
+
+    /// uncommitted or untracked changes in the directory
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
     pub fn write_origin_file<P>(&self, path: P) -> std::io::Result<()>
     where
         P: AsRef<Path>,
@@ -92,9 +195,310 @@ impl Origin {
         let yaml_string = serde_yaml::to_string(&self).unwrap();
 
         // Write the YAML string to a file
-        let mut file = File::create(path.as_ref().join("ORIGIN"))?;
+        let mut file = File::create(path.as_ref().join(ORIGIN_FILE))?;
         file.write_all(yaml_string.as_bytes())?;
 
         Ok(())
     }
+
+    /// written by a dryrun or `ioc stage`, not by a deployment
+    pub fn is_dryrun(&self) -> bool {
+        self.deployment.dryrun
+    }
+
+    /// read the ORIGIN file from the directory `path`, e.g. a deployed IOC.
+    pub fn read_origin_file<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let yaml_string = fs::read_to_string(path.as_ref().join(ORIGIN_FILE))?;
+        serde_yaml::from_str(&yaml_string)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// compare the recorded commit with HEAD of the git repository containing `source`.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - directory of the IOC definition.
+    ///
+    /// # Returns
+    ///
+    /// Relation of the source HEAD to the recorded commit.
+    pub fn compare_with_source<P: AsRef<Path>>(&self, source: P) -> Relation {
+        if self.commit.is_empty() {
+            return Relation::Unknown("no commit recorded in ORIGIN".to_string());
+        }
+        match compare_commits(source.as_ref(), &self.commit) {
+            Ok(relation) => relation,
+            Err(e) => Relation::Unknown(e.message().to_string()),
+        }
+    }
+}
+
+/// tag pointing at the HEAD of the git repository containing `dir`, if any
+pub fn head_tag<P: AsRef<Path>>(dir: P) -> Option<String> {
+    let repo = Repository::discover(dir).ok()?;
+    let mut options = git2::DescribeOptions::new();
+    options.describe_tags().max_candidates_tags(0);
+    let tag = repo.describe(&options).ok()?.format(None).ok();
+    tag
+}
+
+/// check for modified or untracked files below `dir`
+fn is_dirty(repo: &Repository, dir: &Path) -> bool {
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).include_ignored(false);
+    if let Some(relative) = repo
+        .workdir()
+        .and_then(|w| w.canonicalize().ok())
+        .and_then(|w| dir.strip_prefix(w).ok().map(Path::to_path_buf))
+    {
+        if !relative.as_os_str().is_empty() {
+            options.pathspec(relative);
+        }
+    }
+    repo.statuses(Some(&mut options))
+        .map(|statuses| !statuses.is_empty())
+        .unwrap_or_default()
+}
+
+/// short summary of a commit, used to report the commits between two deployments
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitSummary {
+    pub id: String,
+    pub summary: String,
+}
+
+impl std::fmt::Display for CommitSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.id, self.summary)
+    }
+}
+
+/// relation of the source repository HEAD to a deployed commit
+#[derive(Debug, Clone, PartialEq)]
+pub enum Relation {
+    /// source HEAD is the deployed commit
+    UpToDate,
+    /// source HEAD contains commits that are not deployed yet
+    Ahead(Vec<CommitSummary>),
+    /// deployed commit contains commits that are not in the source HEAD
+    Behind(Vec<CommitSummary>),
+    /// both contain commits the other one doesn't have
+    Diverged {
+        ahead: Vec<CommitSummary>,
+        behind: Vec<CommitSummary>,
+    },
+    /// no comparison possible, e.g. no git repository or unknown commit
+    Unknown(String),
+}
+
+fn compare_commits(source: &Path, deployed: &str) -> Result<Relation, git2::Error> {
+    let repo = Repository::discover(source)?;
+    let head = repo.head()?.peel_to_commit()?.id();
+    let deployed = Oid::from_str(deployed)?;
+    // make sure the deployed commit is known to the source repository
+    repo.find_commit(deployed)?;
+
+    if head == deployed {
+        return Ok(Relation::UpToDate);
+    }
+    let ahead = commit_log(&repo, head, deployed)?;
+    let behind = commit_log(&repo, deployed, head)?;
+    trace!("ahead: {}, behind: {}", ahead.len(), behind.len());
+    Ok(match (ahead.is_empty(), behind.is_empty()) {
+        (false, true) => Relation::Ahead(ahead),
+        (true, false) => Relation::Behind(behind),
+        _ => Relation::Diverged { ahead, behind },
+    })
+}
+
+/// commits reachable from `from` but not from `hide`, newest first
+fn commit_log(repo: &Repository, from: Oid, hide: Oid) -> Result<Vec<CommitSummary>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(from)?;
+    revwalk.hide(hide)?;
+    revwalk
+        .map(|oid| {
+            let commit = repo.find_commit(oid?)?;
+            Ok(CommitSummary {
+                id: commit
+                    .as_object()
+                    .short_id()?
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+                summary: commit.summary().unwrap_or_default().to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::new_test_ioc;
+    use tempfile::tempdir;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn deployment_flags() {
+        let deployment = Deployment::new(&true, &false);
+        assert!(deployment.force);
+        assert!(!deployment.dryrun);
+        assert_eq!(deployment.tool, PackageData::new().get_name());
+        assert_eq!(deployment.version, PackageData::new().get_version());
+        assert!(!deployment.user.is_empty());
+        assert!(!deployment.host.is_empty());
+    }
+
+    #[test]
+    fn write_origin_file_with_deployment() -> std::io::Result<()> {
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01")?;
+        let temp_dir = tempdir()?;
+
+        let deployment = Deployment::new(&false, &true).with_profile(&Some("dev".to_string()));
+        let origin = Origin::from_ioc(&test_ioc, &deployment);
+        origin.write_origin_file(temp_dir.path())?;
+
+        let content = std::fs::read_to_string(temp_dir.path().join(ORIGIN_FILE))?;
+        assert!(content.contains(&format!("schema: {}", ORIGIN_SCHEMA_VERSION)));
+        assert!(content.contains("dryrun: true"));
+        assert!(content.contains("profile: dev"));
+        assert!(content.contains("host: iochost"));
+        assert!(content.contains("port: 12345"));
+        Ok(())
+    }
+
+    /// commit an empty tree with `message` on HEAD
+    fn commit(repo: &Repository, message: &str) -> Oid {
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree_id = repo.index().unwrap().write_tree().unwrap();
+        let tree = repo.find_tree(tree_id).unwrap();
+        let parents: Vec<git2::Commit> = repo
+            .head()
+            .ok()
+            .and_then(|h| h.peel_to_commit().ok())
+            .into_iter()
+            .collect();
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn read_origin_file_roundtrip() -> io::Result<()> {
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01")?;
+        let temp_dir = tempdir()?;
+
+        Origin::from_ioc(&test_ioc, &Deployment::new(&true, &false))
+            .write_origin_file(temp_dir.path())?;
+        let origin = Origin::read_origin_file(temp_dir.path())?;
+        assert_eq!(origin.schema, ORIGIN_SCHEMA_VERSION);
+        assert!(origin.deployment.force);
+        assert_eq!(origin.ioc.unwrap().port, 12345);
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_directory() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let repo = Repository::init(temp_dir.path()).unwrap();
+        commit(&repo, "first");
+        fs::create_dir_all(temp_dir.path().join("IOC01"))?;
+        fs::create_dir_all(temp_dir.path().join("IOC02"))?;
+        assert!(!Origin::new(temp_dir.path().join("IOC01")).dirty);
+
+        fs::write(temp_dir.path().join("IOC02/startup.iocsh"), "untracked")?;
+        assert!(!Origin::new(temp_dir.path().join("IOC01")).dirty);
+        assert!(Origin::new(temp_dir.path().join("IOC02")).dirty);
+        assert!(Origin::new(temp_dir.path()).dirty);
+        Ok(())
+    }
+
+    #[test]
+    fn tagged_head() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let repo = Repository::init(temp_dir.path()).unwrap();
+        let first = commit(&repo, "first");
+        assert_eq!(head_tag(temp_dir.path()), None);
+
+        let first = repo.find_object(first, None).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.tag("v1.0", &first, &signature, "release", false)
+            .unwrap();
+        assert_eq!(head_tag(temp_dir.path()), Some("v1.0".to_string()));
+
+        // only the tag of HEAD itself
+        commit(&repo, "second");
+        assert_eq!(head_tag(temp_dir.path()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn read_origin_file_missing() {
+        assert!(Origin::read_origin_file("./tests/does_not_exist").is_err());
+    }
+
+    #[test]
+    fn compare_with_source() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let repo = Repository::init(temp_dir.path()).unwrap();
+        let first = commit(&repo, "first");
+        let second = commit(&repo, "second");
+        commit(&repo, "third");
+
+        let deployed = Origin {
+            commit: first.to_string(),
+            ..Default::default()
+        };
+        match deployed.compare_with_source(temp_dir.path()) {
+            Relation::Ahead(commits) => {
+                assert_eq!(commits.len(), 2);
+                assert_eq!(commits[0].summary, "third");
+                assert_eq!(commits[1].summary, "second");
+            }
+            r => panic!("unexpected relation {:?}", r),
+        }
+
+        let head = Origin::new(temp_dir.path());
+        assert_eq!(
+            head.compare_with_source(temp_dir.path()),
+            Relation::UpToDate
+        );
+
+        // move HEAD back, the deployment is now newer than the source
+        let second = repo.find_commit(second).unwrap();
+        repo.reset(second.as_object(), git2::ResetType::Soft, None)
+            .unwrap();
+        assert!(matches!(
+            head.compare_with_source(temp_dir.path()),
+            Relation::Behind(c) if c.len() == 1
+        ));
+
+        // branch off from `second`, the histories diverge
+        commit(&repo, "fourth");
+        assert!(matches!(
+            head.compare_with_source(temp_dir.path()),
+            Relation::Diverged { ahead, behind } if ahead.len() == 1 && behind.len() == 1
+        ));
+
+        let unknown = Origin {
+            commit: "c00ffee".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            unknown.compare_with_source(temp_dir.path()),
+            Relation::Unknown(_)
+        ));
+        Ok(())
+    }
 }
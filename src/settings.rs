@@ -1,10 +1,16 @@
-use crate::log_macros::{cross, tick};
+use crate::file_system::{IgnoreRules, LayoutSettings};
+use crate::ioc::IocType;
+use crate::log_macros::{exclaim, tick};
+use crate::stage::{render, run_dir};
 use colored::Colorize;
-use config::{Config, ConfigError, File};
-use log::{debug, error, trace};
+use config::{Config, ConfigError, File, FileFormat};
+use log::{debug, trace, warn};
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
 use std::{
-    env, io,
+    env,
+    ffi::OsString,
+    fmt, io,
     path::{Path, PathBuf},
 };
 use tera::Tera;
@@ -15,26 +21,64 @@ const IOC_DIR: &str = "ioc";
 const CONFIG_DIR: &str = ".config";
 const HOME: &str = "HOME";
 const XDG_CONFIG_HOME: &str = "XDG_CONFIG_HOME";
+/// runtime directory of the user, the default location of the stage root
+const XDG_RUNTIME_DIR: &str = "XDG_RUNTIME_DIR";
+/// environment variable selecting the profile, `--profile` takes precedence
+pub const IOC_PROFILE: &str = "IOC_PROFILE";
+/// table of the named profiles
+const PROFILES: &str = "profiles";
+/// system wide config file, the first layer
+const SYSTEM_CONFIG: &str = "/etc/ioc/config.toml";
+/// environment variables overriding settings, `__` separates the table from the key
+const ENV_PREFIX: &str = "IOC_";
+const ENV_SEPARATOR: &str = "__";
+/// origin of the layer of environment variables
+pub const ENVIRONMENT_LAYER: &str = "environment";
+/// origin of the layer of `--set` arguments
+pub const CLI_LAYER: &str = "--set";
+/// defaults of settings missing in the site configuration
+const DEFAULT_DEPLOY: &str = "/opt/ioc/";
+const DEFAULT_SHELLBOX: &str = "/opt/ioc/hosts/";
+const DEFAULT_TEMPLATES: &str = "/etc/ioc/templates/*.tera";
 
-#[derive(Debug, Deserialize)]
-#[allow(unused)]
+/// the `[filesystem]` table, paths interpolated
+#[derive(Debug, Clone, PartialEq)]
 pub struct Filesystem {
+    /// root of the stage directories
     pub stage: String,
     pub deploy: String,
     pub shellbox: String,
 }
 
-#[derive(Debug, Deserialize)]
-#[allow(unused)]
+/// the `[app]` table
+#[derive(Debug, Clone, PartialEq)]
 pub struct App {
+    /// glob of the templates, e.g. `templates/**/*.tera`
     pub template_directory: String,
+    /// default layout of deployed IOCs, `layout`, `preserve`, `symlinks` and `non_utf8_names`
+    pub layout: LayoutSettings,
+    /// files not staged, gitignore patterns applied before the `.iocignore` of an IOC
+    pub ignore: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
-#[allow(unused)]
+/// the `[policy]` table, rules for deployments with these settings
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// IOCs are only deployed from a clean checkout of a tagged commit
+    pub require_tag: bool,
+    /// every deployment is confirmed interactively
+    pub confirm: bool,
+}
+
+/// Site configuration, read once, typed and validated, and passed to the commands.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Settings {
+    /// name of the active profile, if any
+    pub profile: Option<String>,
     pub filesystem: Filesystem,
     pub app: App,
+    pub policy: Policy,
 }
 
 /// Returns the config file path as `String` if there is one. When `None` is provided then the config
@@ -92,41 +136,658 @@ fn config_from_home() -> Option<String> {
     })
 }
 
+/// where the config files are looked for, besides the user config file
+#[derive(Debug, Clone)]
+struct ConfigSearch {
+    /// the system wide config file
+    system: Option<PathBuf>,
+    /// a directory in the git repository with the project config file at its root
+    project: Option<PathBuf>,
+}
+
+impl ConfigSearch {
+    /// the system config file and the git repository of the current directory
+    #[cfg(not(test))]
+    fn new() -> Self {
+        ConfigSearch {
+            system: Some(PathBuf::from(SYSTEM_CONFIG)),
+            project: env::current_dir().ok(),
+        }
+    }
+
+    /// tests don't depend on the config of the machine or of the checkout they run in
+    #[cfg(test)]
+    fn new() -> Self {
+        ConfigSearch {
+            system: None,
+            project: None,
+        }
+    }
+}
+
 impl Settings {
-    pub fn build(config_file: &str) -> Result<Config, ConfigError> {
-        let cfg_file = match cfg_path_to_string(Some(Path::new(config_file))) {
-            Some(p) => {
-                debug!("{} found config file: {}", tick!(), p);
-                p
+    /// The layers of the site configuration, in the order they are merged:
+    ///
+    /// - the system config file `/etc/ioc/config.toml`,
+    /// - the user config file, `config_file` or else found by `cfg_path_to_string`,
+    /// - the project config file `ioc.toml` at the root of the git repository of the current
+    ///   directory,
+    /// - the settings of the `profile`, `[profiles.<profile>]` of the files above,
+    /// - `IOC_` environment variables, e.g. `IOC_FILESYSTEM__DEPLOY` for `filesystem.deploy`,
+    /// - the `key=value` pairs of `--set`.
+    ///
+    /// At least one config file is required.
+    pub fn layers(
+        config_file: &str,
+        profile: Option<&str>,
+        sets: &[String],
+    ) -> Result<Vec<Layer>, ConfigError> {
+        Self::search_layers(&ConfigSearch::new(), config_file, profile, sets)
+    }
+
+    /// the layers, with the system and project config files looked for as `search` says
+    fn search_layers(
+        search: &ConfigSearch,
+        config_file: &str,
+        profile: Option<&str>,
+        sets: &[String],
+    ) -> Result<Vec<Layer>, ConfigError> {
+        if !config_file.is_empty() && !Path::new(config_file).is_file() {
+            return Err(ConfigError::Message(format!(
+                "config file {:?} not found",
+                config_file
+            )));
+        }
+        let mut files: Vec<PathBuf> = Vec::new();
+        let candidates = [
+            search.system.clone(),
+            cfg_path_to_string(Some(config_file)).map(PathBuf::from),
+            search.project.as_deref().and_then(project_config),
+        ];
+        for file in candidates.into_iter().flatten() {
+            let canonical = file.canonicalize().unwrap_or(file.clone());
+            if file.is_file()
+                && !files
+                    .iter()
+                    .any(|f| f.canonicalize().ok() == Some(canonical.clone()))
+            {
+                debug!("{} found config file: {:?}", tick!(), file);
+                files.push(file);
             }
-            None => {
-                error!("{} {}", cross!(), "missing config file".red());
-                panic!("config file is mandatory")
+        }
+        if files.is_empty() {
+            return Err(ConfigError::Message(format!(
+                "missing config file, none given and none found in {}, ${}, ${}/{}/{}, ${}/{}/{}/{}, ${}/{} or the git repository",
+                SYSTEM_CONFIG,
+                IOC_CONFIG_FILE,
+                XDG_CONFIG_HOME,
+                IOC_DIR,
+                IOC_CONFIG_NAME,
+                HOME,
+                CONFIG_DIR,
+                IOC_DIR,
+                IOC_CONFIG_NAME,
+                HOME,
+                IOC_CONFIG_NAME
+            )));
+        }
+
+        let mut layers = Vec::new();
+        for file in files {
+            let config = Config::builder()
+                .add_source(File::from(file.as_path()).required(true))
+                .build()?;
+            layers.push(Layer {
+                origin: file.display().to_string(),
+                config,
+            });
+        }
+        if let Some(name) = profile {
+            let config = profile_layer(&merge(&layers)?, name)?;
+            // the files defining the profile table
+            let table = format!("{}.{}", PROFILES, name);
+            let files: Vec<&str> = layers
+                .iter()
+                .filter(|l| l.config.get::<config::Value>(&table).is_ok())
+                .map(|l| l.origin.as_str())
+                .collect();
+            layers.push(Layer {
+                origin: format!("profile {} ({})", name, files.join(", ")),
+                config,
+            });
+        }
+        let environment = environment_overrides(env::vars_os());
+        layers.push(Layer {
+            origin: ENVIRONMENT_LAYER.to_string(),
+            config: overrides(environment)?,
+        });
+        let cli = sets
+            .iter()
+            .map(|set| match set.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    Ok((key.trim().to_string(), value.to_string()))
+                }
+                _ => Err(ConfigError::Message(format!(
+                    "invalid --set {:?}, expected key=value",
+                    set
+                ))),
+            })
+            .collect::<Result<_, _>>()?;
+        layers.push(Layer {
+            origin: CLI_LAYER.to_string(),
+            config: overrides(cli)?,
+        });
+        for layer in &layers {
+            trace!(
+                "{} config layer {}: {:?}",
+                tick!(),
+                layer.origin,
+                layer.config
+            );
+        }
+        Ok(layers)
+    }
+
+    /// Read and validate the layered site configuration with the settings of `profile`, see
+    /// `Settings::layers` and `Settings::from_config`.
+    pub fn load(
+        config_file: &str,
+        profile: Option<&str>,
+        sets: &[String],
+    ) -> Result<Settings, Vec<VerifyError>> {
+        let config = Settings::layers(config_file, profile, sets)
+            .and_then(|layers| merge(&layers))
+            .map_err(|e| vec![VerifyError::Config(e)])?;
+        Settings::from_config(&config, profile)
+    }
+
+    /// Typed settings of the site configuration, missing settings take their defaults and
+    /// `[filesystem]` paths are interpolated. `profile` names the profile already applied to
+    /// `config`. The template set is verified: all templates required by the IOC types exist,
+    /// includes and `extends` resolve, and every template renders with the context of a sample
+    /// IOC. All problems found are returned.
+    pub fn from_config(
+        config: &Config,
+        profile: Option<&str>,
+    ) -> Result<Settings, Vec<VerifyError>> {
+        let (settings, errors) = Settings::read(config, profile);
+        match errors.is_empty() {
+            true => Ok(settings),
+            false => Err(errors),
+        }
+    }
+
+    /// The settings of `config` and the problems found, see `Settings::from_config`. Invalid
+    /// settings take their defaults, paths which can't be interpolated are empty.
+    pub(crate) fn read(config: &Config, profile: Option<&str>) -> (Settings, Vec<VerifyError>) {
+        let mut errors = Vec::new();
+        let stage = setting(config, "filesystem.stage", None, &mut errors);
+        let deploy: String = setting(
+            config,
+            "filesystem.deploy",
+            DEFAULT_DEPLOY.into(),
+            &mut errors,
+        );
+        let shellbox: String = setting(
+            config,
+            "filesystem.shellbox",
+            DEFAULT_SHELLBOX.into(),
+            &mut errors,
+        );
+        let template_directory = setting(
+            config,
+            "app.template_directory",
+            DEFAULT_TEMPLATES.to_string(),
+            &mut errors,
+        );
+        let layout = setting(config, "app", LayoutSettings::default(), &mut errors);
+        let ignore: Vec<String> = setting(config, "app.ignore", Vec::new(), &mut errors);
+        let policy = setting(config, "policy", Policy::default(), &mut errors);
+
+        let mut path = |key: &str, value: Result<String, String>| {
+            value.unwrap_or_else(|reason| {
+                errors.push(VerifyError::Path {
+                    key: key.to_string(),
+                    reason,
+                });
+                String::new()
+            })
+        };
+        let filesystem = Filesystem {
+            stage: path("filesystem.stage", resolve_stage_root(stage)),
+            deploy: path("filesystem.deploy", interpolate(&deploy)),
+            shellbox: path("filesystem.shellbox", interpolate(&shellbox)),
+        };
+        if let Err(e) = IgnoreRules::new(".", &ignore) {
+            errors.push(VerifyError::Config(ConfigError::Message(format!(
+                "invalid ignore pattern: {}",
+                e
+            ))));
+        }
+        errors.extend(verify_templates(&template_directory));
+
+        let settings = Settings {
+            profile: profile.map(str::to_string),
+            filesystem,
+            app: App {
+                template_directory,
+                layout,
+                ignore,
+            },
+            policy,
+        };
+        (settings, errors)
+    }
+
+    /// Directories written by a deployment: `deploy` and `shellbox` are writable directories,
+    /// the stage root is one or can be created. All problems found are returned.
+    pub fn check_directories(&self) -> Vec<VerifyError> {
+        let mut errors = Vec::new();
+        let mut check = |key: &str, path: &str, create: bool| {
+            if let Err(reason) = check_writable_dir(Path::new(path), create) {
+                errors.push(VerifyError::Directory {
+                    key: key.to_string(),
+                    path: path.to_string(),
+                    reason,
+                });
             }
         };
+        check("filesystem.stage", &self.filesystem.stage, true);
+        check("filesystem.deploy", &self.filesystem.deploy, false);
+        check("filesystem.shellbox", &self.filesystem.shellbox, false);
+        errors
+    }
+}
+
+/// The profile selected by `--profile`, or else by `$IOC_PROFILE`.
+pub fn selected_profile(cli: &Option<String>) -> Option<String> {
+    cli.clone()
+        .or_else(|| env::var(IOC_PROFILE).ok())
+        .filter(|name| !name.is_empty())
+}
+
+/// a source of settings, see `Settings::layers`
+#[derive(Debug, Clone)]
+pub struct Layer {
+    /// where the settings come from, e.g. the path of a config file
+    pub origin: String,
+    pub config: Config,
+}
+
+/// the layers merged, later layers take precedence setting by setting
+pub fn merge(layers: &[Layer]) -> Result<Config, ConfigError> {
+    layers
+        .iter()
+        .fold(Config::builder(), |builder, layer| {
+            builder.add_source(layer.config.clone())
+        })
+        .build()
+}
+
+/// environment variable overriding the setting `key`, e.g. `IOC_FILESYSTEM__DEPLOY`
+pub fn env_name(key: &str) -> String {
+    format!(
+        "{}{}",
+        ENV_PREFIX,
+        key.to_uppercase().replace('.', ENV_SEPARATOR)
+    )
+}
+
+/// `ioc.toml` at the root of the git repository of `dir`
+fn project_config(dir: &Path) -> Option<PathBuf> {
+    let repo = git2::Repository::discover(dir).ok()?;
+    Some(repo.workdir()?.join(IOC_CONFIG_NAME))
+}
+
+/// the settings of `[profiles.<name>]` of `config`
+fn profile_layer(config: &Config, name: &str) -> Result<Config, ConfigError> {
+    let profiles = match config.get_table(PROFILES) {
+        Ok(profiles) => profiles,
+        Err(ConfigError::NotFound(_)) => Default::default(),
+        Err(e) => return Err(e),
+    };
+    let profile = match profiles.get(name) {
+        Some(profile) => profile.clone(),
+        None => {
+            let mut known: Vec<&String> = profiles.keys().collect();
+            known.sort();
+            return Err(ConfigError::Message(format!(
+                "unknown profile {:?}, the config defines {:?}",
+                name, known
+            )));
+        }
+    };
+    let mut leaves = Vec::new();
+    flatten(String::new(), profile, &mut leaves)?;
+    leaves
+        .into_iter()
+        .try_fold(Config::builder(), |builder, (key, value)| {
+            builder.set_override(key, value)
+        })?
+        .build()
+}
+
+/// Settings keys and values of the `IOC_X__Y` variables in `vars`. Variables which aren't valid
+/// UTF-8 are skipped with a warning.
+fn environment_overrides(
+    vars: impl Iterator<Item = (OsString, OsString)>,
+) -> Vec<(String, String)> {
+    vars.filter_map(|(name, value)| {
+        let lossy = name.to_string_lossy().into_owned();
+        let key = lossy.strip_prefix(ENV_PREFIX)?;
+        if !key.contains(ENV_SEPARATOR) {
+            return None;
+        }
+        match (name.to_str(), value.into_string()) {
+            (Some(_), Ok(value)) => Some((key.to_lowercase().replace(ENV_SEPARATOR, "."), value)),
+            _ => {
+                warn!("{} ignoring {}, it is not valid UTF-8", exclaim!(), lossy);
+                None
+            }
+        }
+    })
+    .collect()
+}
+
+/// Settings from `key`/`value` pairs. A value is parsed as TOML, e.g. `true`, `42` or
+/// `["*.md"]`, and taken as string if it isn't valid TOML.
+fn overrides(pairs: Vec<(String, String)>) -> Result<Config, ConfigError> {
+    pairs
+        .into_iter()
+        .try_fold(Config::builder(), |builder, (key, value)| {
+            let toml = Config::builder()
+                .add_source(File::from_str(
+                    &format!("value = {}", value),
+                    FileFormat::Toml,
+                ))
+                .build()
+                .and_then(|c| c.get::<config::Value>("value"));
+            match toml {
+                Ok(parsed) => builder.set_override(key, parsed),
+                Err(_) => builder.set_override(key, value),
+            }
+        })?
+        .build()
+}
+
+/// the leaves of the table `value` with their dotted keys
+fn flatten(
+    prefix: String,
+    value: config::Value,
+    leaves: &mut Vec<(String, config::Value)>,
+) -> Result<(), ConfigError> {
+    match value.kind {
+        config::ValueKind::Table(_) => {
+            for (key, value) in value.into_table()? {
+                let key = match prefix.is_empty() {
+                    true => key,
+                    false => format!("{}.{}", prefix, key),
+                };
+                flatten(key, value, leaves)?;
+            }
+        }
+        _ => leaves.push((prefix, value)),
+    }
+    Ok(())
+}
+
+/// Setting `key`, `default` if missing. A setting of the wrong type is added to `errors`.
+fn setting<T: DeserializeOwned>(
+    config: &Config,
+    key: &str,
+    default: T,
+    errors: &mut Vec<VerifyError>,
+) -> T {
+    match config.get::<T>(key) {
+        Ok(value) => value,
+        Err(ConfigError::NotFound(_)) => default,
+        Err(e) => {
+            errors.push(VerifyError::Config(e));
+            default
+        }
+    }
+}
 
-        let s = Config::builder()
-            .add_source(File::with_name(cfg_file.as_str()).required(true))
-            .build()?;
-        trace!("{} {:?}", tick!(), s);
-        Ok(s)
+/// `path` is a writable directory. With `create`, a missing directory is fine if the closest
+/// existing parent is writable.
+fn check_writable_dir(path: &Path, create: bool) -> Result<(), String> {
+    let existing = match (path.exists(), create) {
+        (true, _) => path,
+        (false, false) => return Err("does not exist".to_string()),
+        (false, true) => match path.ancestors().skip(1).find(|p| p.exists()) {
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) => parent,
+            None => Path::new("."),
+        },
+    };
+    if !existing.is_dir() {
+        return Err(format!("{:?} is not a directory", existing));
+    }
+    // the one reliable test, permissions alone don't tell about ACLs or read-only mounts
+    tempfile::Builder::new()
+        .prefix(".ioc-")
+        .tempfile_in(existing)
+        .map(|_| ())
+        .map_err(|e| format!("{:?} is not writable: {}", existing, e))
+}
+
+/// Verify the template set of the glob `template_dir`.
+fn verify_templates(template_dir: &str) -> Vec<VerifyError> {
+    // parsing resolves `extends` as well
+    let mut tera = match Tera::new(template_dir) {
+        Ok(tera) => tera,
+        Err(e) => {
+            return vec![VerifyError::Parse {
+                templates: template_dir.to_string(),
+                reason: render::error_chain(&e),
+            }]
+        }
+    };
+    if tera.get_template_names().next().is_none() {
+        return vec![VerifyError::NoTemplates(template_dir.to_string())];
     }
 
-    pub fn verify(config: &Config) -> Result<(), ConfigError> {
-        let template_dir = config.get::<String>("app.template_directory")?;
-        let tera = match Tera::new(&template_dir) {
-            Ok(t) => t,
-            Err(e) => {
-                error!("Parsing error(s): {}", e);
-                std::process::exit(1);
+    let mut errors = Vec::new();
+    for ioc_type in IocType::ALL {
+        for template in ioc_type.required_templates() {
+            if tera.templates.contains_key(*template) {
+                continue;
+            }
+            let error = VerifyError::MissingTemplate {
+                template: template.to_string(),
+                ioc_type,
+            };
+            match ioc_type {
+                IocType::Compiled => errors.push(error),
+                // sites without Python IOCs may lack it, staging a Python IOC fails instead
+                IocType::Python => warn!("{} {}", exclaim!(), error),
+            }
+        }
+    }
+    for (template, include) in render::unresolved_includes(&tera) {
+        errors.push(VerifyError::UnresolvedInclude { template, include });
+    }
+    if errors.is_empty() {
+        // dry-run, unresolved includes would fail here again
+        render::register_filters(&mut tera);
+        let context = render::sample_context();
+        let mut names: Vec<&str> = tera.get_template_names().collect();
+        names.sort();
+        for template in names {
+            if let Err(e) = tera.render(template, &context) {
+                errors.push(VerifyError::Render {
+                    template: template.to_string(),
+                    reason: render::error_chain(&e),
+                });
             }
+        }
+    }
+    errors
+}
+
+/// Root of the stage directories, `filesystem.stage` interpolated, or a directory private to
+/// the user without one.
+fn resolve_stage_root(stage: Option<String>) -> Result<String, String> {
+    let stage = match stage {
+        Some(stage) => stage,
+        None => return Ok(fallback_stage_root()),
+    };
+    // formerly the name of an environment variable, `/ioc/stage` appended
+    if is_variable_name(&stage) {
+        if let Ok(value) = env::var(&stage) {
+            warn!(
+                "{} `filesystem.stage = \"{}\"` is deprecated, use \"${{{}}}/ioc/stage\"",
+                exclaim!(),
+                stage,
+                stage
+            );
+            return Ok(value + "/ioc/stage");
+        }
+        if stage == XDG_RUNTIME_DIR {
+            return Ok(fallback_stage_root());
+        }
+    }
+    interpolate(&stage)
+}
+
+/// Stage root without a configured one, private to the user: below `$XDG_RUNTIME_DIR`, or
+/// `/tmp/ioc-<uid>` if it isn't set, e.g. in sudo, ssh or cron sessions.
+fn fallback_stage_root() -> String {
+    match env::var(XDG_RUNTIME_DIR) {
+        Ok(runtime_dir) if !runtime_dir.is_empty() => format!("{}/ioc/stage", runtime_dir),
+        _ => run_dir::private_tmp_dir()
+            .join("stage")
+            .display()
+            .to_string(),
+    }
+}
+
+/// e.g. `XDG_RUNTIME_DIR`
+fn is_variable_name(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Interpolate `${VAR}` and `${VAR:-default}` with environment variables, and a leading `~`
+/// with `$HOME`. Unset variables without a default are an error.
+pub fn interpolate(value: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = value;
+    if rest == "~" || rest.starts_with("~/") {
+        let home = env::var(HOME).map_err(|_| "`~` used, but HOME is not set".to_string())?;
+        result.push_str(&home);
+        rest = &rest[1..];
+    }
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated `${{` in {:?}", value))?;
+        let expression = &rest[start + 2..start + end];
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
         };
-        if tera.get_template_names().collect::<Vec<_>>().is_empty() {
-            return Err(ConfigError::Message(
-                "The specified template path does not contain valid templates".to_string(),
-            ));
+        if name.is_empty() {
+            return Err(format!("empty variable name in {:?}", value));
+        }
+        match (env::var(name), default) {
+            (Ok(val), Some(default)) if val.is_empty() => result.push_str(default),
+            (Ok(val), _) => result.push_str(&val),
+            (Err(_), Some(default)) => result.push_str(default),
+            (Err(_), None) => {
+                return Err(format!(
+                    "environment variable {} is not set, use ${{{}:-default}} for a default",
+                    name, name
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// problem found in the site configuration
+#[derive(Debug)]
+pub enum VerifyError {
+    /// config file missing or unreadable, or a setting of the wrong type
+    Config(ConfigError),
+    /// a path setting can't be interpolated
+    Path { key: String, reason: String },
+    /// a directory setting isn't usable
+    Directory {
+        key: String,
+        path: String,
+        reason: String,
+    },
+    /// the templates can't be parsed, e.g. syntax errors or an unknown parent
+    Parse { templates: String, reason: String },
+    /// the template glob doesn't match any template
+    NoTemplates(String),
+    /// a template required by an IOC type is missing
+    MissingTemplate { template: String, ioc_type: IocType },
+    /// an include doesn't resolve to a template
+    UnresolvedInclude { template: String, include: String },
+    /// the template fails to render with the context of a sample IOC
+    Render { template: String, reason: String },
+}
+
+impl VerifyError {
+    /// a problem of the template set
+    pub fn is_template(&self) -> bool {
+        matches!(
+            self,
+            VerifyError::Parse { .. }
+                | VerifyError::NoTemplates(_)
+                | VerifyError::MissingTemplate { .. }
+                | VerifyError::UnresolvedInclude { .. }
+                | VerifyError::Render { .. }
+        )
+    }
+
+    /// the setting the problem belongs to, if any
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            VerifyError::Path { key, .. } | VerifyError::Directory { key, .. } => Some(key),
+            e if e.is_template() => Some("app.template_directory"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Config(e) => write!(f, "invalid configuration: {}", e),
+            VerifyError::Path { key, reason } => write!(f, "{}: {}", key, reason),
+            VerifyError::Directory { key, path, reason } => {
+                write!(f, "{} = {:?}: {}", key, path, reason)
+            }
+            VerifyError::Parse { templates, reason } => {
+                write!(f, "failed to parse templates {:?}: {}", templates, reason)
+            }
+            VerifyError::NoTemplates(templates) => write!(
+                f,
+                "the template path {:?} does not contain valid templates",
+                templates
+            ),
+            VerifyError::MissingTemplate { template, ioc_type } => write!(
+                f,
+                "template '{}' required for {:?} IOCs is missing",
+                template, ioc_type
+            ),
+            VerifyError::UnresolvedInclude { template, include } => write!(
+                f,
+                "template '{}' includes '{}', which does not exist",
+                template, include
+            ),
+            VerifyError::Render { template, reason } => {
+                write!(f, "template '{}' fails to render: {}", template, reason)
+            }
         }
-        Ok(())
     }
 }
 
@@ -154,15 +815,516 @@ mod tests {
     }
 
     #[test]
+    #[serial]
     fn config_from_config_path_none() {
         assert_eq!(config_from_config_path(), None);
     }
 
     #[test]
+    #[serial]
     fn config_from_xdg_path_none() {
         assert_eq!(config_from_xdg_path(), None);
     }
 
+    /// site config with the templates in `template_dir`
+    fn config_with_templates(template_dir: &Path) -> Config {
+        Config::builder()
+            .set_override(
+                "app.template_directory",
+                format!("{}/*.tera", template_dir.display()),
+            )
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    /// site config with the repository templates and the `overrides`
+    fn site_config(overrides: &[(&str, config::Value)]) -> Config {
+        let mut builder = Config::builder()
+            .add_source(config_with_templates(Path::new("templates")))
+            .set_override("app.template_directory", "templates/**/*.tera")
+            .unwrap();
+        for (key, value) in overrides {
+            builder = builder.set_override(*key, value.clone()).unwrap();
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    #[serial]
+    fn verify_success() {
+        let settings = Settings::load("./tests/config/test_stage.toml", None, &[]).unwrap();
+        assert_eq!(settings.filesystem.stage, "tests/tmp/stage/");
+        assert_eq!(settings.filesystem.deploy, "tests/tmp/deploy/ioc/");
+        assert_eq!(settings.app.template_directory, "templates/**/*.tera");
+    }
+
+    #[test]
+    #[serial]
+    fn defaults() {
+        let settings = Settings::from_config(&site_config(&[]), None).unwrap();
+        assert_eq!(settings.filesystem.stage, fallback_stage_root());
+        assert_eq!(settings.filesystem.deploy, DEFAULT_DEPLOY);
+        assert_eq!(settings.filesystem.shellbox, DEFAULT_SHELLBOX);
+        assert_eq!(settings.app.layout, LayoutSettings::default());
+        assert!(settings.app.ignore.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn missing_config_file() {
+        let temp_dir = tempdir().unwrap();
+        env::remove_var(IOC_CONFIG_FILE);
+        env::set_var(HOME, temp_dir.path());
+        env::set_var(XDG_CONFIG_HOME, temp_dir.path());
+        let errors = Settings::load("", None, &[]).unwrap_err();
+        assert!(
+            matches!(&errors[..], [VerifyError::Config(e)] if e.to_string().contains("missing config file"))
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn site_layout() {
+        let settings = Settings::from_config(
+            &site_config(&[
+                ("app.layout", "preserve".into()),
+                ("app.preserve", vec!["cfg", "db"].into()),
+            ]),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.app.layout,
+            LayoutSettings {
+                layout: Some(crate::file_system::Layout::Preserve),
+                preserve: Some(vec!["cfg".to_string(), "db".to_string()]),
+                ..Default::default()
+            }
+        );
+
+        let config = site_config(&[("app.layout", "nested".into())]);
+        assert!(Settings::from_config(&config, None).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn profiles() {
+        let config = site_config(&[
+            ("filesystem.deploy", "/opt/ioc/".into()),
+            ("filesystem.shellbox", "/opt/ioc/hosts/".into()),
+            ("app.layout", "preserve".into()),
+            ("profiles.dev.filesystem.deploy", "dev/deploy/".into()),
+            ("profiles.production.policy.require_tag", true.into()),
+            ("profiles.production.policy.confirm", true.into()),
+        ]);
+
+        let with_profile = |name: &str| {
+            let base = Layer {
+                origin: "base".to_string(),
+                config: config.clone(),
+            };
+            let profile = Layer {
+                origin: format!("profile {}", name),
+                config: profile_layer(&config, name).unwrap(),
+            };
+            merge(&[base, profile]).unwrap()
+        };
+
+        let base = Settings::from_config(&config, None).unwrap();
+        assert_eq!(base.profile, None);
+        assert_eq!(base.policy, Policy::default());
+
+        // inherits everything not set by the profile
+        let dev = Settings::from_config(&with_profile("dev"), Some("dev")).unwrap();
+        assert_eq!(dev.profile, Some("dev".to_string()));
+        assert_eq!(dev.filesystem.deploy, "dev/deploy/");
+        assert_eq!(dev.filesystem.shellbox, "/opt/ioc/hosts/");
+        assert_eq!(dev.app.layout.layout, base.app.layout.layout);
+
+        let production =
+            Settings::from_config(&with_profile("production"), Some("production")).unwrap();
+        assert_eq!(production.filesystem, base.filesystem);
+        assert!(production.policy.require_tag);
+        assert!(production.policy.confirm);
+
+        let error = profile_layer(&config, "prod").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains(r#"unknown profile "prod", the config defines ["dev", "production"]"#));
+    }
+
+    #[test]
+    #[serial]
+    fn profile_selection() {
+        env::remove_var(IOC_PROFILE);
+        assert_eq!(selected_profile(&None), None);
+        env::set_var(IOC_PROFILE, "dev");
+        assert_eq!(selected_profile(&None), Some("dev".to_string()));
+        assert_eq!(
+            selected_profile(&Some("production".to_string())),
+            Some("production".to_string())
+        );
+        env::remove_var(IOC_PROFILE);
+    }
+
+    #[test]
+    #[serial]
+    fn layered() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let user = temp_dir.path().join("ioc.toml");
+        std::fs::write(
+            &user,
+            "[filesystem]\ndeploy = \"/opt/ioc/\"\nshellbox = \"/opt/ioc/hosts/\"\n\n\
+             [app]\ntemplate_directory = \"templates/**/*.tera\"\n\n\
+             [profiles.dev.filesystem]\ndeploy = \"dev/deploy/\"\n",
+        )?;
+        let user = user.to_str().unwrap();
+        env::set_var("IOC_FILESYSTEM__SHELLBOX", "${IOC_TEST_HOSTS:-hosts}/");
+        env::set_var("IOC_POLICY__CONFIRM", "true");
+
+        let layers = Settings::layers(user, Some("dev"), &[]).unwrap();
+        let origins: Vec<&str> = layers.iter().map(|l| l.origin.as_str()).collect();
+        let profile = format!("profile dev ({})", user);
+        assert_eq!(origins, vec![user, &profile, "environment", "--set"]);
+
+        let sets = ["filesystem.deploy=/tmp/ioc".to_string()];
+        let settings = Settings::load(user, Some("dev"), &sets).unwrap();
+        assert_eq!(settings.filesystem.deploy, "/tmp/ioc");
+        assert_eq!(settings.filesystem.shellbox, "hosts/");
+        assert!(settings.policy.confirm);
+        let settings = Settings::load(user, Some("dev"), &[]).unwrap();
+        assert_eq!(settings.filesystem.deploy, "dev/deploy/");
+
+        env::remove_var("IOC_FILESYSTEM__SHELLBOX");
+        env::remove_var("IOC_POLICY__CONFIRM");
+        let settings = Settings::load(user, None, &[]).unwrap();
+        assert_eq!(settings.filesystem.deploy, "/opt/ioc/");
+        assert!(!settings.policy.confirm);
+
+        assert!(Settings::layers(user, None, &["deploy".to_string()]).is_err());
+        assert!(Settings::layers("does/not/exist.toml", None, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn layered_files() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let user = temp_dir.path().join("user.toml");
+        std::fs::write(
+            &user,
+            "[filesystem]\nstage = \"user/stage/\"\ndeploy = \"user/deploy/\"\n\
+             shellbox = \"user/hosts/\"\n",
+        )?;
+        let user = user.to_str().unwrap();
+        let project = temp_dir.path().join("project");
+        std::fs::create_dir_all(project.join("src"))?;
+        git2::Repository::init(&project).unwrap();
+        std::fs::write(
+            project.join(IOC_CONFIG_NAME),
+            "[filesystem]\ndeploy = \"project/deploy/\"\nshellbox = \"project/hosts/\"\n\n\
+             [app]\ntemplate_directory = \"project/templates/*.tera\"\n",
+        )?;
+        let search = ConfigSearch {
+            system: Some(PathBuf::from("tests/config/system.toml")),
+            project: Some(project.join("src")),
+        };
+        env::set_var("IOC_FILESYSTEM__SHELLBOX", "environment/hosts/");
+        let sets = ["app.template_directory=cli/*.tera".to_string()];
+
+        let layers = Settings::search_layers(&search, user, None, &sets).unwrap();
+        env::remove_var("IOC_FILESYSTEM__SHELLBOX");
+        let origins: Vec<&str> = layers.iter().map(|l| l.origin.as_str()).collect();
+        let project_config = project.join(IOC_CONFIG_NAME);
+        assert_eq!(
+            origins,
+            vec![
+                "tests/config/system.toml",
+                user,
+                project_config.to_str().unwrap(),
+                "environment",
+                "--set"
+            ]
+        );
+        let config = merge(&layers).unwrap();
+        let value = |key: &str| config.get::<String>(key).unwrap();
+        assert_eq!(value("policy.require_tag"), "true");
+        assert_eq!(value("filesystem.stage"), "user/stage/");
+        assert_eq!(value("filesystem.deploy"), "project/deploy/");
+        assert_eq!(value("filesystem.shellbox"), "environment/hosts/");
+        assert_eq!(value("app.template_directory"), "cli/*.tera");
+
+        // outside of a git repository, no project config
+        let search = ConfigSearch {
+            project: Some(temp_dir.path().to_path_buf()),
+            ..search
+        };
+        let layers = Settings::search_layers(&search, user, None, &[]).unwrap();
+        assert_eq!(
+            merge(&layers)
+                .unwrap()
+                .get::<String>("filesystem.deploy")
+                .unwrap(),
+            "user/deploy/"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn override_values() {
+        let config = overrides(vec![
+            ("policy.confirm".to_string(), "true".to_string()),
+            ("app.ignore".to_string(), r#"["*.md", "*~"]"#.to_string()),
+            ("filesystem.deploy".to_string(), "/opt/ioc/".to_string()),
+        ])
+        .unwrap();
+        assert!(config.get::<bool>("policy.confirm").unwrap());
+        assert_eq!(
+            config.get::<Vec<String>>("app.ignore").unwrap(),
+            vec!["*.md", "*~"]
+        );
+        assert_eq!(
+            config.get::<String>("filesystem.deploy").unwrap(),
+            "/opt/ioc/"
+        );
+    }
+
+    #[test]
+    fn environment_variables() {
+        use std::os::unix::ffi::OsStringExt;
+        let var = |name: &[u8], value: &[u8]| {
+            (
+                OsString::from_vec(name.to_vec()),
+                OsString::from_vec(value.to_vec()),
+            )
+        };
+        let vars = vec![
+            var(b"IOC_FILESYSTEM__DEPLOY", b"/opt/ioc/"),
+            var(b"IOC_POLICY__CONFIRM", b"\xff"),
+            var(b"IOC_APP__\xff", b"true"),
+            var(b"IOC_PROFILE", b"dev"),
+            var(b"BAD", b"\xff"),
+        ];
+        assert_eq!(
+            environment_overrides(vars.into_iter()),
+            vec![("filesystem.deploy".to_string(), "/opt/ioc/".to_string())]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn site_ignore() {
+        let config = site_config(&[("app.ignore", vec!["*.md", "test/"].into())]);
+        let settings = Settings::from_config(&config, None).unwrap();
+        assert_eq!(settings.app.ignore, vec!["*.md", "test/"]);
+
+        let config = site_config(&[("app.ignore", vec!["[z-a]"].into())]);
+        assert!(Settings::from_config(&config, None).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn all_problems() {
+        env::remove_var("IOC_TEST_SHELLBOX_ROOT");
+        let config = site_config(&[
+            ("filesystem.deploy", vec!["/opt/ioc"].into()),
+            ("filesystem.shellbox", "${IOC_TEST_SHELLBOX_ROOT}".into()),
+            ("app.layout", "nested".into()),
+        ]);
+        let errors = Settings::from_config(&config, None).unwrap_err();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors
+            .iter()
+            .any(|e| matches!(e, VerifyError::Path { key, .. } if key == "filesystem.shellbox")));
+    }
+
+    #[test]
+    #[serial]
+    fn directories() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let deploy = temp_dir.path().join("deploy");
+        std::fs::create_dir(&deploy)?;
+        std::fs::write(temp_dir.path().join("file"), "")?;
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        let mut settings = Settings::from_config(&site_config(&[]), None).unwrap();
+        settings.filesystem = Filesystem {
+            // created when needed
+            stage: path(&temp_dir.path().join("stage/ioc")),
+            deploy: path(&deploy),
+            shellbox: path(&deploy.join("hosts")),
+        };
+        let errors = settings.check_directories();
+        assert!(
+            matches!(&errors[..], [VerifyError::Directory { key, .. }] if key == "filesystem.shellbox")
+        );
+
+        settings.filesystem.stage = path(&temp_dir.path().join("file/stage"));
+        settings.filesystem.shellbox = path(&deploy);
+        let errors = settings.check_directories();
+        assert!(
+            matches!(&errors[..], [VerifyError::Directory { key, .. }] if key == "filesystem.stage")
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn interpolation() {
+        env::set_var("IOC_TEST_STAGE", "/run/user/1000");
+        env::set_var("IOC_TEST_EMPTY", "");
+        env::remove_var("IOC_TEST_UNSET");
+
+        assert_eq!(interpolate("/opt/ioc/").unwrap(), "/opt/ioc/");
+        assert_eq!(
+            interpolate("${IOC_TEST_STAGE}/ioc/stage").unwrap(),
+            "/run/user/1000/ioc/stage"
+        );
+        assert_eq!(
+            interpolate("${IOC_TEST_UNSET:-/tmp}/ioc/${IOC_TEST_EMPTY:-stage}").unwrap(),
+            "/tmp/ioc/stage"
+        );
+        assert_eq!(
+            interpolate("~/ioc").unwrap(),
+            format!("{}/ioc", env::var(HOME).unwrap())
+        );
+        // only a leading `~`, `$` without braces is literal
+        assert_eq!(interpolate("a~b/$HOME").unwrap(), "a~b/$HOME");
+
+        let error = interpolate("${IOC_TEST_UNSET}/ioc").unwrap_err();
+        assert!(error.contains("IOC_TEST_UNSET is not set"));
+        assert!(interpolate("${IOC_TEST_STAGE").is_err());
+        assert!(interpolate("${}").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn stage_root() {
+        env::set_var("IOC_TEST_RUNTIME", "/run/user/1000");
+        let config = |stage: &str| resolve_stage_root(Some(stage.to_string()));
+        assert_eq!(
+            config("${IOC_TEST_RUNTIME}/ioc/stage").unwrap(),
+            "/run/user/1000/ioc/stage"
+        );
+        assert_eq!(config("stage/").unwrap(), "stage/");
+        // deprecated name of an environment variable
+        assert_eq!(
+            config("IOC_TEST_RUNTIME").unwrap(),
+            "/run/user/1000/ioc/stage"
+        );
+        assert!(config("${IOC_TEST_NOT_SET}").is_err());
+        assert_eq!(resolve_stage_root(None).unwrap(), fallback_stage_root());
+    }
+
+    #[test]
+    #[serial]
+    fn fallback_stage_roots() {
+        let runtime_dir = env::var_os(XDG_RUNTIME_DIR);
+        env::set_var(XDG_RUNTIME_DIR, "/run/user/1000");
+        assert_eq!(fallback_stage_root(), "/run/user/1000/ioc/stage");
+        assert_eq!(
+            resolve_stage_root(Some(XDG_RUNTIME_DIR.to_string())).unwrap(),
+            "/run/user/1000/ioc/stage"
+        );
+
+        // private to the user, not shared in /tmp
+        env::remove_var(XDG_RUNTIME_DIR);
+        let private = format!("/tmp/ioc-{}/stage", users::get_current_uid());
+        assert_eq!(fallback_stage_root(), private);
+        assert_eq!(
+            resolve_stage_root(Some(XDG_RUNTIME_DIR.to_string())).unwrap(),
+            private
+        );
+        if let Some(runtime_dir) = runtime_dir {
+            env::set_var(XDG_RUNTIME_DIR, runtime_dir);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn verify_no_templates() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let errors =
+            Settings::from_config(&config_with_templates(temp_dir.path()), None).unwrap_err();
+        assert!(matches!(errors[..], [VerifyError::NoTemplates(_)]));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn verify_parse_error() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        std::fs::write(
+            temp_dir.path().join("startup.tera"),
+            "{% extends \"base.tera\" %}",
+        )?;
+        let errors =
+            Settings::from_config(&config_with_templates(temp_dir.path()), None).unwrap_err();
+        assert!(matches!(errors[..], [VerifyError::Parse { .. }]));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn verify_template_errors() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        std::fs::write(
+            temp_dir.path().join("header.tera"),
+            "{% if config.port %}{% include \"missing.tera\" %}{% endif %}",
+        )?;
+        let errors =
+            Settings::from_config(&config_with_templates(temp_dir.path()), None).unwrap_err();
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            VerifyError::MissingTemplate { template, ioc_type: IocType::Compiled } if template == "startup.tera"
+        )));
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            VerifyError::UnresolvedInclude { template, include } if template == "header.tera" && include == "missing.tera"
+        )));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn verify_without_python_template() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        std::fs::write(
+            temp_dir.path().join("startup.tera"),
+            "{{ IOC | shell_quote }}",
+        )?;
+        assert!(Settings::from_config(&config_with_templates(temp_dir.path()), None).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn verify_unset_variable() {
+        env::remove_var("IOC_TEST_DEPLOY_ROOT");
+        let config = site_config(&[("filesystem.deploy", "${IOC_TEST_DEPLOY_ROOT}/ioc".into())]);
+        let errors = Settings::from_config(&config, None).unwrap_err();
+        assert!(errors.iter().any(|e| e
+            .to_string()
+            .contains("filesystem.deploy: environment variable IOC_TEST_DEPLOY_ROOT is not set")));
+    }
+
+    #[test]
+    #[serial]
+    fn verify_render_error() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        std::fs::write(
+            temp_dir.path().join("startup.tera"),
+            "{{ IOC }} {{ config.hots }}",
+        )?;
+        std::fs::write(temp_dir.path().join("python_startup.tera"), "{{ IOC }}")?;
+        let errors =
+            Settings::from_config(&config_with_templates(temp_dir.path()), None).unwrap_err();
+        assert!(
+            matches!(&errors[..], [VerifyError::Render { template, .. }] if template == "startup.tera")
+        );
+        Ok(())
+    }
+
     #[test]
     #[serial]
     fn config_from_home_none() {
@@ -185,7 +1347,7 @@ mod tests {
         env::set_var("IOC_CONFIG_FILE", cfg_file.as_os_str());
 
         let result = config_from_config_path();
-        
+
         // Clean up the environment variable we set
         env::remove_var("IOC_CONFIG_FILE");
 
@@ -1,5 +1,5 @@
 use crate::ioc;
-use crate::ioc::IOC;
+use crate::ioc::{python_ioc, IocType, IOC};
 use log::trace;
 use tera::{Context, Error, Tera};
 
@@ -26,10 +26,20 @@ impl ShellBoxConfig {
             .base_dir
             .to_owned()
             .unwrap_or(ioc.destination.to_str().unwrap_or_default().to_owned());
+        let mut ioc_config = ioc.config.ioc.clone();
+        // Python IOCs are started by their launcher, unless configured otherwise
+        if matches!(ioc.ioc_type, IocType::Python) && python_ioc::uses_launcher(&ioc_config) {
+            ioc_config.command = ioc
+                .destination
+                .join(python_ioc::PYTHON_LAUNCHER)
+                .to_string_lossy()
+                .into_owned();
+            ioc_config.command_args = String::new();
+        }
         ShellBoxConfig {
             base_dir,
             name: ioc.name.to_owned(),
-            ioc_config: ioc.config.ioc.clone(),
+            ioc_config,
         }
     }
 
@@ -55,15 +65,18 @@ impl ShellBoxConfig {
 
 #[cfg(test)]
 mod tests {
-    use crate::ioc::IOC;
+    use crate::ioc::{python_ioc, IocType, IOC};
     use crate::settings::Settings;
     use crate::shellbox;
+    use serial_test::serial;
     use std::path::Path;
     use tempfile::tempdir;
 
     fn get_test_ioc() -> std::io::Result<IOC> {
-        let settings = Settings::build("./tests/config/test_stage.toml").unwrap();
-        let template_dir = settings.get::<String>("app.template_directory").unwrap();
+        let template_dir = Settings::load("./tests/config/test_stage.toml", None, &[])
+            .unwrap()
+            .app
+            .template_directory;
 
         let temp_dir = tempdir()?;
         let stage_dir = temp_dir.path().join("stage");
@@ -82,6 +95,7 @@ mod tests {
     }
 
     #[test]
+    #[serial]
     fn from_ioc_shellboxconfig() -> std::io::Result<()> {
         let test_ioc = get_test_ioc()?;
 
@@ -102,4 +116,22 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[serial]
+    fn from_python_ioc_shellboxconfig() -> std::io::Result<()> {
+        let mut test_ioc = get_test_ioc()?;
+        test_ioc.ioc_type = IocType::Python;
+
+        let config = shellbox::ShellBoxConfig::from_ioc(&test_ioc);
+        let launcher = test_ioc.destination.join(python_ioc::PYTHON_LAUNCHER);
+        assert_eq!(config.ioc_config.command, launcher.to_str().unwrap());
+        assert_eq!(config.ioc_config.command_args, "");
+
+        // an explicit command wins
+        test_ioc.config.ioc.command = "python".to_string();
+        let config = shellbox::ShellBoxConfig::from_ioc(&test_ioc);
+        assert_eq!(config.ioc_config.command, "python");
+        Ok(())
+    }
 }
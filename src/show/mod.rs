@@ -0,0 +1,525 @@
+use std::io;
+use std::path::Path;
+
+use config::{Config, File};
+use log::trace;
+use serde::Serialize;
+use tera::{Map, Value};
+
+use crate::file_system::LayoutSettings;
+use crate::ioc::{self, ioc_config};
+use crate::settings::{self, Layer, Settings, CLI_LAYER, ENVIRONMENT_LAYER, IOC_PROFILE};
+
+/// source of settings not set anywhere
+const DEFAULT: &str = "default";
+
+/// output format of `ioc config show`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+/// an effective setting and where its value comes from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Entry {
+    /// dotted key, e.g. `filesystem.deploy`
+    #[serde(skip)]
+    pub key: String,
+    pub value: Value,
+    /// default, a config file, an environment variable or the command line
+    pub source: String,
+    /// problems of the setting
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+}
+
+impl Entry {
+    fn new(key: impl Into<String>, value: Value, source: impl Into<String>) -> Self {
+        Entry {
+            key: key.into(),
+            value,
+            source: source.into(),
+            problems: Vec::new(),
+        }
+    }
+}
+
+/// Print the effective site settings and, with `ioc`, the effective config of the IOC, each
+/// value with the place it comes from. Broken settings don't stop the output, their problems
+/// are listed next to the values. Returns the number of problems.
+pub fn config_show(
+    config_file: &str,
+    profile: Option<&str>,
+    cli_profile: &Option<String>,
+    sets: &[String],
+    ioc: &Option<String>,
+    format: Format,
+) -> io::Result<usize> {
+    let mut problems = Vec::new();
+    let layers = Settings::layers(config_file, profile, sets).unwrap_or_else(|e| {
+        problems.push(e.to_string());
+        Vec::new()
+    });
+    let merged = settings::merge(&layers).map_err(to_io_error)?;
+    let (settings, errors) = Settings::read(&merged, profile);
+    let mut site = site_entries(&settings, &layers, &merged, cli_profile);
+    for error in errors {
+        problems.extend(attach(&mut site, error.key(), error.to_string()));
+    }
+    let ioc = match ioc {
+        Some(dir) => {
+            let dir = Path::new(dir);
+            let mut entries = match ioc_entries(dir, &site) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    Vec::new()
+                }
+            };
+            for problem in ioc_config::validate(dir) {
+                problems.extend(attach(&mut entries, problem.key(), problem.to_string()));
+            }
+            Some(entries)
+        }
+        None => None,
+    };
+    let count = problems.len()
+        + site
+            .iter()
+            .chain(ioc.iter().flatten())
+            .map(|e| e.problems.len())
+            .sum::<usize>();
+
+    let origins: Vec<&str> = layers.iter().map(|l| l.origin.as_str()).collect();
+    let output = match format {
+        Format::Toml => {
+            let mut output = format!("# layers: {}\n", origins.join(", "));
+            for problem in &problems {
+                output.push_str(&format!("# problem: {}\n", problem));
+            }
+            output.push_str(&to_toml(&site));
+            if let Some(ioc) = &ioc {
+                output.push_str(&to_toml(ioc));
+            }
+            output
+        }
+        Format::Json => {
+            let mut root = Map::new();
+            root.insert("layers".to_string(), tera::to_value(&origins).unwrap());
+            root.insert("problems".to_string(), tera::to_value(&problems).unwrap());
+            root.insert("settings".to_string(), nested(&site));
+            if let Some(ioc) = &ioc {
+                root.insert("ioc_config".to_string(), nested(ioc));
+            }
+            format!("{:#}\n", Value::Object(root))
+        }
+    };
+    print!("{}", output);
+    Ok(count)
+}
+
+/// Add the `problem` to the entry of `key`. Returns the problem if there is no such entry.
+fn attach(entries: &mut [Entry], key: Option<&str>, problem: String) -> Option<String> {
+    match entries.iter_mut().find(|e| Some(e.key.as_str()) == key) {
+        Some(entry) => {
+            entry.problems.push(problem);
+            None
+        }
+        None => Some(problem),
+    }
+}
+
+/// the effective site settings after defaults, the sources taken from the `layers`
+fn site_entries(
+    settings: &Settings,
+    layers: &[Layer],
+    merged: &Config,
+    cli_profile: &Option<String>,
+) -> Vec<Entry> {
+    let profile_source = match (&settings.profile, cli_profile) {
+        (None, _) => DEFAULT.to_string(),
+        (Some(_), Some(_)) => "--profile".to_string(),
+        (Some(_), None) => format!("{} {}", ENVIRONMENT_LAYER, IOC_PROFILE),
+    };
+    let layout = settings.app.layout.or(&LayoutSettings::builtin());
+    let values = [
+        ("filesystem.stage", to_value(&settings.filesystem.stage)),
+        ("filesystem.deploy", to_value(&settings.filesystem.deploy)),
+        (
+            "filesystem.shellbox",
+            to_value(&settings.filesystem.shellbox),
+        ),
+        (
+            "app.template_directory",
+            to_value(&settings.app.template_directory),
+        ),
+        ("app.layout", to_value(&layout.layout)),
+        ("app.preserve", to_value(&layout.preserve)),
+        ("app.symlinks", to_value(&layout.symlinks)),
+        ("app.non_utf8_names", to_value(&layout.non_utf8_names)),
+        ("app.ignore", to_value(&settings.app.ignore)),
+        ("policy.require_tag", to_value(&settings.policy.require_tag)),
+        ("policy.confirm", to_value(&settings.policy.confirm)),
+    ];
+    let mut entries = vec![Entry::new(
+        "profile",
+        to_value(&settings.profile),
+        profile_source,
+    )];
+    for (key, value) in values {
+        let mut source = site_source(layers, key);
+        // the configured value, if interpolated
+        if let (Ok(raw), Value::String(effective)) = (merged.get::<String>(key), &value) {
+            if &raw != effective {
+                source = format!("{}, from {:?}", source, raw);
+            }
+        }
+        entries.push(Entry::new(key, value, source));
+    }
+    entries
+}
+
+/// the last layer setting `key`
+fn site_source(layers: &[Layer], key: &str) -> String {
+    match layers
+        .iter()
+        .rev()
+        .find(|l| l.config.get::<config::Value>(key).is_ok())
+    {
+        Some(layer) if layer.origin == ENVIRONMENT_LAYER => {
+            format!("{} {}", ENVIRONMENT_LAYER, settings::env_name(key))
+        }
+        Some(layer) if layer.origin == CLI_LAYER => format!("{} {}", CLI_LAYER, key),
+        Some(layer) => layer.origin.clone(),
+        None => DEFAULT.to_string(),
+    }
+}
+
+/// The effective config of the IOC in `dir`, after defaults and hostname normalisation. The
+/// layout settings not set fall back to the `site` entries, the type is detected from `dir`.
+fn ioc_entries(dir: &Path, site: &[Entry]) -> io::Result<Vec<Entry>> {
+    let base = dir.join("config");
+    let base = base.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("IOC directory is not valid UTF-8: {}", dir.display()),
+        )
+    })?;
+    let config = ioc_config::Settings::build(base).map_err(to_io_error)?;
+    let effective: ioc_config::Settings = config.try_deserialize().map_err(to_io_error)?;
+    let file = Config::builder()
+        .add_source(File::with_name(base).required(false))
+        .build()
+        .map_err(to_io_error)?;
+    let file_name = ioc_config::config_file(dir)
+        .map(|f| f.display().to_string())
+        .unwrap_or_else(|| base.to_string());
+    trace!("IOC config of {:?}: {:?}", dir, file_name);
+
+    let mut leaves = Vec::new();
+    flatten("ioc", tera::to_value(&effective.ioc).unwrap(), &mut leaves);
+    // an empty table would be a key of the table before
+    if !effective.vars.is_empty() {
+        flatten(
+            "vars",
+            tera::to_value(&effective.vars).unwrap(),
+            &mut leaves,
+        );
+    }
+    // keys of a table before its sub-tables
+    leaves.sort_by(|(a, _), (b, _)| table_of(a).cmp(table_of(b)));
+    Ok(leaves
+        .into_iter()
+        .map(|(key, value)| {
+            let source = match file.get::<config::Value>(&key) {
+                Ok(_)
+                    if key == "ioc.host"
+                        && file.get::<String>(&key).ok().as_deref() != value.as_str() =>
+                {
+                    format!("{}, lowercased", file_name)
+                }
+                Ok(_) => file_name.clone(),
+                Err(_) => DEFAULT.to_string(),
+            };
+            match (value, key.strip_prefix("ioc.")) {
+                (Value::Null, Some("type")) => match ioc::check_ioc_type(dir, &effective.ioc) {
+                    Some(ioc_type) => {
+                        Entry::new(key, to_value(&ioc_type), format!("{}, detected", DEFAULT))
+                    }
+                    None => Entry::new(key, Value::Null, source),
+                },
+                (Value::Null, Some(name)) => {
+                    match site.iter().find(|e| e.key == format!("app.{}", name)) {
+                        Some(entry) if entry.source == DEFAULT => {
+                            Entry::new(key, entry.value.clone(), DEFAULT)
+                        }
+                        Some(entry) => Entry::new(
+                            key,
+                            entry.value.clone(),
+                            format!("{}, {}", entry.key, entry.source),
+                        ),
+                        None => Entry::new(key, Value::Null, source),
+                    }
+                }
+                (value, _) => Entry::new(key, value, source),
+            }
+        })
+        .collect())
+}
+
+/// table of the dotted `key`
+fn table_of(key: &str) -> &str {
+    key.rsplit_once('.')
+        .map(|(table, _)| table)
+        .unwrap_or_default()
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    tera::to_value(value).unwrap_or(Value::Null)
+}
+
+fn to_io_error(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// the leaves of the object `value` with their dotted keys, arrays are leaves
+fn flatten(prefix: &str, value: Value, leaves: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                flatten(&format!("{}.{}", prefix, key), value, leaves);
+            }
+        }
+        value => leaves.push((prefix.to_string(), value)),
+    }
+}
+
+/// the entries as nested JSON objects, every value with its source
+fn nested(entries: &[Entry]) -> Value {
+    let mut root = Map::new();
+    for entry in entries {
+        let mut keys: Vec<&str> = entry.key.split('.').collect();
+        let last = keys.pop().unwrap_or_default();
+        let mut table = &mut root;
+        for key in keys {
+            let value = table
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !value.is_object() {
+                *value = Value::Object(Map::new());
+            }
+            table = value.as_object_mut().unwrap();
+        }
+        table.insert(last.to_string(), to_value(entry));
+    }
+    Value::Object(root)
+}
+
+/// The entries as TOML, grouped in tables, every value followed by its source and problems as
+/// comment. Settings without a value are commented out.
+fn to_toml(entries: &[Entry]) -> String {
+    let mut output = String::new();
+    let mut current = None;
+    let mut lines: Vec<(String, String)> = Vec::new();
+    let flush = |lines: &mut Vec<(String, String)>, output: &mut String| {
+        let width = lines.iter().map(|(l, _)| l.len()).max().unwrap_or_default();
+        for (line, comment) in lines.drain(..) {
+            output.push_str(&format!("{:width$}  # {}\n", line, comment, width = width));
+        }
+    };
+    for entry in entries {
+        let (table, key) = match entry.key.rsplit_once('.') {
+            Some((table, key)) => (Some(table), key),
+            None => (None, entry.key.as_str()),
+        };
+        if table != current {
+            flush(&mut lines, &mut output);
+            if let Some(table) = table {
+                let table: Vec<String> = table.split('.').map(toml_key).collect();
+                output.push_str(&format!("\n[{}]\n", table.join(".")));
+            }
+            current = table;
+        }
+        let line = match entry.value {
+            Value::Null => format!("# {} is not set", toml_key(key)),
+            ref value => format!("{} = {}", toml_key(key), value),
+        };
+        let comment = entry
+            .problems
+            .iter()
+            .fold(entry.source.clone(), |comment, problem| {
+                format!("{}; problem: {}", comment, problem)
+            });
+        lines.push((line, comment));
+    }
+    flush(&mut lines, &mut output);
+    output
+}
+
+/// `key`, quoted unless it is a bare key
+fn toml_key(key: &str) -> String {
+    match !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        true => key.to_string(),
+        false => Value::String(key.to_string()).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::env;
+    use std::fs;
+    use tempfile::tempdir;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    #[serial]
+    fn provenance() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let site = temp_dir.path().join("ioc.toml");
+        fs::write(
+            &site,
+            "[filesystem]\ndeploy = \"/opt/ioc/\"\nstage = \"${IOC_TEST_SHOW:-/tmp}/stage\"\n\n\
+             [app]\ntemplate_directory = \"templates/**/*.tera\"\n",
+        )?;
+        let site = site.to_str().unwrap();
+        env::remove_var("IOC_TEST_SHOW");
+        env::set_var("IOC_POLICY__CONFIRM", "true");
+        let sets = ["filesystem.deploy=/tmp/ioc".to_string()];
+        let layers = Settings::layers(site, None, &sets).unwrap();
+        env::remove_var("IOC_POLICY__CONFIRM");
+        let merged = settings::merge(&layers).unwrap();
+        let settings = Settings::from_config(&merged, None).unwrap();
+
+        let entries = site_entries(&settings, &layers, &merged, &None);
+        let entry = |key: &str| entries.iter().find(|e| e.key == key).unwrap().clone();
+        let source = |key: &str| entry(key).source;
+        assert_eq!(source("profile"), DEFAULT);
+        assert_eq!(entry("app.layout").value, "flat");
+        assert_eq!(source("app.layout"), DEFAULT);
+        assert_eq!(
+            entry("app.preserve").value,
+            tera::to_value(["cfg"]).unwrap()
+        );
+        assert_eq!(entry("app.non_utf8_names").value, "warn");
+        assert_eq!(source("filesystem.deploy"), "--set filesystem.deploy");
+        assert_eq!(source("filesystem.shellbox"), DEFAULT);
+        assert_eq!(
+            source("filesystem.stage"),
+            format!("{}, from \"${{IOC_TEST_SHOW:-/tmp}}/stage\"", site)
+        );
+        assert_eq!(source("policy.confirm"), "environment IOC_POLICY__CONFIRM");
+
+        let toml = to_toml(&entries);
+        assert!(toml.contains("\n[filesystem]\nstage = \"/tmp/stage\" "));
+        assert!(toml.contains("\nconfirm = true       # environment IOC_POLICY__CONFIRM\n"));
+        assert!(toml.starts_with("# profile is not set  # default\n"));
+        assert!(toml
+            .lines()
+            .any(|l| l.starts_with("symlinks = \"follow\" ") && l.ends_with("  # default")));
+
+        let json = nested(&entries);
+        assert_eq!(json["filesystem"]["deploy"]["value"], "/tmp/ioc");
+        assert_eq!(json["policy"]["confirm"]["value"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn ioc_provenance() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        fs::write(
+            temp_dir.path().join("config.toml"),
+            "[ioc]\nhost = \"IOCHost\"\nport = 20000\nsymlinks = \"keep\"\n\n[vars]\nmotors = 4\n",
+        )?;
+        fs::write(temp_dir.path().join("startup.iocsh"), "")?;
+        let site = [
+            Entry::new(
+                "app.layout",
+                Value::from("preserve"),
+                "/etc/ioc/config.toml",
+            ),
+            Entry::new("app.non_utf8_names", Value::from("warn"), DEFAULT),
+        ];
+        let entries = ioc_entries(temp_dir.path(), &site)?;
+        let entry = |key: &str| entries.iter().find(|e| e.key == key).unwrap().clone();
+        let file = temp_dir.path().join("config.toml").display().to_string();
+
+        assert_eq!(entry("ioc.host").value, "iochost");
+        assert_eq!(entry("ioc.host").source, format!("{}, lowercased", file));
+        assert_eq!(entry("ioc.port").source, file);
+        assert_eq!(entry("ioc.user").value, "control2");
+        assert_eq!(entry("ioc.user").source, DEFAULT);
+        assert_eq!(entry("ioc.permissions.read_only").source, DEFAULT);
+        assert_eq!(entry("vars.motors").value, 4);
+        assert_eq!(entry("vars.motors").source, file);
+        assert_eq!(entry("ioc.type").value, "compiled");
+        assert_eq!(entry("ioc.type").source, "default, detected");
+        assert_eq!(entry("ioc.layout").value, "preserve");
+        assert_eq!(
+            entry("ioc.layout").source,
+            "app.layout, /etc/ioc/config.toml"
+        );
+        assert_eq!(entry("ioc.non_utf8_names").value, "warn");
+        assert_eq!(entry("ioc.non_utf8_names").source, DEFAULT);
+        assert_eq!(entry("ioc.symlinks").value, "keep");
+        assert_eq!(entry("ioc.symlinks").source, file);
+
+        let toml = to_toml(&entries);
+        assert_eq!(toml.matches("\n[ioc]\n").count(), 1);
+        assert!(toml.contains("\n[ioc.permissions]\n"));
+        assert!(toml.contains("# group is not set"));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn broken_config() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let site = temp_dir.path().join("ioc.toml");
+        fs::write(
+            &site,
+            "[filesystem]\ndeploy = \"${IOC_TEST_SHOW}/ioc\"\n\n\
+             [app]\ntemplate_directory = \"none/*.tera\"\n",
+        )?;
+        let site = site.to_str().unwrap();
+        env::remove_var("IOC_TEST_SHOW");
+        fs::write(temp_dir.path().join("config.toml"), "[ioc]\nport = 0\n")?;
+        let ioc = Some(temp_dir.path().display().to_string());
+
+        // deploy, template_directory, the missing host and port
+        assert_eq!(config_show(site, None, &None, &[], &ioc, Format::Toml)?, 4);
+        // no layers for an unknown profile, the defaults are still shown
+        assert_ne!(
+            config_show(site, Some("missing"), &None, &[], &None, Format::Json)?,
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn problems_attached() {
+        let mut entries = [Entry::new("ioc.port", Value::from(0), DEFAULT)];
+        assert_eq!(
+            attach(&mut entries, Some("ioc.port"), "zero".to_string()),
+            None
+        );
+        assert_eq!(
+            attach(&mut entries, None, "broken".to_string()),
+            Some("broken".to_string())
+        );
+        assert!(to_toml(&entries).contains("port = 0  # default; problem: zero\n"));
+        assert_eq!(nested(&entries)["ioc"]["port"]["problems"][0], "zero");
+    }
+
+    #[test]
+    fn toml_keys() {
+        assert_eq!(toml_key("deploy"), "deploy");
+        assert_eq!(toml_key("motor x"), "\"motor x\"");
+    }
+}
@@ -1,19 +1,249 @@
-use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
 
 use colored::Colorize;
 use log::{debug, error, info, warn};
 
-use crate::ioc::IocType;
+use crate::install::check_ioc_list;
+use crate::ioc::{ioc_config, python_ioc, IocType};
 use crate::log_macros::{cross, exclaim};
-use crate::origin::Origin;
-use crate::{
-    file_system::{self, CopyMode},
-    ioc::IOC,
-    log_macros::tick,
-};
+use crate::origin::{Deployment, Origin};
+use crate::settings::Settings;
+use crate::{file_system, ioc::IOC, log_macros::tick};
 pub mod render;
+pub mod run_dir;
 
-pub fn stage(ioc: &IOC) -> io::Result<()> {
+/// Stage IOCs for inspection, the stages are kept. A single IOC is staged into `path` itself,
+/// several IOCs into directories named after them below `path`, or without `path` below a new
+/// private directory of the stage root. With `clean`, previous stages are removed from `path`,
+/// or the previous run directories of the user from the stage root, first.
+pub fn ioc_stage(
+    iocs: &Option<Vec<String>>,
+    settings: &Settings,
+    path: &Option<String>,
+    tree: &bool,
+    clean: &bool,
+) -> io::Result<()> {
+    let unique_iocs = check_ioc_list(iocs)?;
+    ioc_config::validate_batch(&unique_iocs)?;
+    let root = PathBuf::from(path.as_deref().unwrap_or(&settings.filesystem.stage));
+    let deploy_root = &settings.filesystem.deploy;
+    let shellbox_root = &settings.filesystem.shellbox;
+    let template_dir = &settings.app.template_directory;
+    let site_layout = &settings.app.layout;
+    let site_ignore = &settings.app.ignore;
+
+    // without a path, a new private directory below the stage root, kept for inspection
+    let run_dir = match path {
+        Some(_) => None,
+        None => {
+            if *clean {
+                clean_run_dirs(&root)?;
+            }
+            Some(run_dir::RunDir::create(&root)?)
+        }
+    };
+    let root = match &run_dir {
+        Some(run_dir) => run_dir.path().to_path_buf(),
+        None => root,
+    };
+    let exact = path.is_some() && unique_iocs.len() == 1;
+    let mut ioc_list = Vec::new();
+    for ioc in IOC::from_list(
+        &unique_iocs,
+        &root,
+        deploy_root,
+        shellbox_root,
+        template_dir,
+    ) {
+        let ioc = ioc
+            .with_site_layout(site_layout)
+            .with_site_ignore(site_ignore)?;
+        ioc_list.push(match exact {
+            true => ioc.with_stage(&root),
+            false => ioc,
+        });
+    }
+    if ioc_list.is_empty() {
+        error!("{} ioc list empty - Finishing process", cross!());
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no IOC to stage"));
+    }
+
+    if path.is_some() {
+        // a previous stage in the exact path is replaced anyway
+        if *clean && !exact {
+            clean_stages(&root, &ioc_list)?;
+        }
+        check_stages(&ioc_list)?;
+    }
+
+    let deployment = Deployment::new(&false, &true).with_profile(&settings.profile);
+    for ioc in &ioc_list {
+        info!("----- {} -----", ioc.name.blue().bold());
+        stage(ioc, &deployment)?;
+        if *tree {
+            println!("{}", tree_summary(&ioc.stage)?);
+        }
+    }
+    if let Some(run_dir) = run_dir {
+        println!("staged in {}", run_dir.keep().display());
+    }
+    Ok(())
+}
+
+/// remove previous stages from `root`, unless it holds the source of an IOC
+fn clean_stages(root: &Path, iocs: &[IOC]) -> io::Result<()> {
+    if !root.exists() {
+        return Ok(());
+    }
+    let canonical_root = root.canonicalize()?;
+    for ioc in iocs {
+        if ioc.source.canonicalize()?.starts_with(&canonical_root) {
+            error!(
+                "{} refusing to clean {:?}, it contains the source of {}",
+                cross!(),
+                root,
+                ioc.name.red()
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stage directory contains an IOC source",
+            ));
+        }
+    }
+    // only the stages, anything else in the directory is left alone
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && is_previous_stage(&entry.path()) {
+            fs::remove_dir_all(entry.path())?;
+            info!("{} removed previous stage {:?}", tick!(), entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Remove the run directories kept by previous `ioc stage` runs of the user from the stage root.
+/// Directories of other users, and those holding anything but stages, e.g. of a running
+/// installation, are left alone.
+fn clean_run_dirs(stage_root: &Path) -> io::Result<()> {
+    if !stage_root.is_dir() {
+        return Ok(());
+    }
+    run_dir::verify_stage_root(stage_root)?;
+    let uid = users::get_current_uid();
+    for entry in fs::read_dir(stage_root)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name();
+        if !metadata.is_dir()
+            || metadata.uid() != uid
+            || !name.to_string_lossy().starts_with(run_dir::RUN_DIR_PREFIX)
+        {
+            continue;
+        }
+        let stages = fs::read_dir(entry.path())?
+            .map(|stage| stage.map(|stage| is_previous_stage(&stage.path())))
+            .collect::<io::Result<Vec<bool>>>()?;
+        if !stages.is_empty() && stages.iter().all(|&stage| stage) {
+            fs::remove_dir_all(entry.path())?;
+            info!("{} removed previous stages in {:?}", tick!(), entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// a stage left by `ioc stage`, its ORIGIN records a dryrun
+fn is_previous_stage(dir: &Path) -> bool {
+    Origin::read_origin_file(dir).is_ok_and(|origin| origin.is_dryrun())
+}
+
+/// The stages in or below a directory given by the user are cleared before staging, so they must
+/// be missing, empty or previous stages, and neither inside nor around the source of an IOC.
+fn check_stages(iocs: &[IOC]) -> io::Result<()> {
+    let sources = iocs
+        .iter()
+        .map(|ioc| ioc.source.canonicalize())
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    for ioc in iocs {
+        let refuse = |reason: String| {
+            error!(
+                "{} refusing to stage {}: {}",
+                cross!(),
+                ioc.name.red(),
+                reason
+            );
+            Err(io::Error::new(io::ErrorKind::InvalidInput, reason))
+        };
+        let stage = canonical_path(&ioc.stage)?;
+        if let Some(source) = sources
+            .iter()
+            .find(|source| stage.starts_with(source) || source.starts_with(&stage))
+        {
+            return refuse(format!(
+                "the stage {:?} overlaps the IOC source {:?}",
+                ioc.stage, source
+            ));
+        }
+        let used = fs::read_dir(&ioc.stage).is_ok_and(|mut entries| entries.next().is_some());
+        if used && !is_previous_stage(&ioc.stage) {
+            return refuse(format!(
+                "{:?} is not empty and not a previous stage",
+                ioc.stage
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `path` canonicalized as far as it exists, the missing rest appended
+fn canonical_path(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("/"));
+    let missing = path.strip_prefix(existing).unwrap_or(Path::new(""));
+    Ok(existing.canonicalize()?.join(missing))
+}
+
+/// tree of the files in `dir`, like the `tree` command
+pub fn tree_summary(dir: &Path) -> io::Result<String> {
+    let mut lines = vec![dir.display().to_string()];
+    let files = tree_lines(dir, "", &mut lines)?;
+    lines.push(format!("\n{} file(s)", files));
+    Ok(lines.join("\n"))
+}
+
+fn tree_lines(dir: &Path, prefix: &str, lines: &mut Vec<String>) -> io::Result<usize> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = 0;
+    for (n, entry) in entries.iter().enumerate() {
+        let last = n + 1 == entries.len();
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let name = match file_type.is_symlink() {
+            true => format!("{} -> {}", name, fs::read_link(entry.path())?.display()),
+            false => name,
+        };
+        lines.push(format!(
+            "{}{} {}",
+            prefix,
+            if last { "└──" } else { "├──" },
+            name
+        ));
+        if file_type.is_dir() {
+            let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+            files += tree_lines(&entry.path(), &prefix, lines)?;
+        } else {
+            files += 1;
+        }
+    }
+    Ok(files)
+}
+
+pub fn stage(ioc: &IOC, deployment: &Deployment) -> io::Result<()> {
     info!("staging {}", ioc.name.blue());
 
     match prep_stage(ioc) {
@@ -28,14 +258,12 @@ pub fn stage(ioc: &IOC) -> io::Result<()> {
         }
     }
 
-    match file_system::copy_recursively(
+    match file_system::copy_recursively_with(
         &ioc.source,
         &ioc.stage,
-        if matches!(ioc.ioc_type, IocType::Python) {
-            CopyMode::FlattenExcept(vec!["cfg".to_string(), "env".to_string()])
-        } else {
-            CopyMode::preserve_directories()
-        },
+        ioc.layout.clone().preserving(render::IOC_TEMPLATE_DIR),
+        &ioc.ignore,
+        &ioc.copy_options,
     ) {
         Ok(_) => debug!(
             "{} copied {:?} -> {:?}",
@@ -53,7 +281,37 @@ pub fn stage(ioc: &IOC) -> io::Result<()> {
         }
     }
 
-    match Origin::new(&ioc.source).write_origin_file(&ioc.stage) {
+    // the IOC templates are read from the source, they are not deployed
+    let ioc_templates = ioc.stage.join(render::IOC_TEMPLATE_DIR);
+    if ioc_templates.is_dir() {
+        for file in render::find_non_templates(&ioc_templates)? {
+            warn!(
+                "{} {:?} is not deployed, `{}/` of an IOC holds template overrides only",
+                exclaim!(),
+                file.strip_prefix(&ioc.stage).unwrap_or(&file),
+                render::IOC_TEMPLATE_DIR
+            );
+        }
+        fs::remove_dir_all(&ioc_templates)?;
+        debug!("{} removed {:?}", tick!(), ioc_templates.as_path());
+    }
+
+    if matches!(ioc.ioc_type, IocType::Python) {
+        let problems = python_ioc::validate(ioc);
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("{} {}", cross!(), problem.to_string().red());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} problem(s) in Python IOC {}", problems.len(), ioc.name),
+            ));
+        }
+        debug!("{} Python IOC validated.", tick!());
+    }
+
+    let origin = Origin::from_ioc(ioc, deployment);
+    match origin.write_origin_file(&ioc.stage) {
         Ok(_) => debug!("{} ORIGIN file written.", tick!()),
         Err(e) => {
             error!(
@@ -65,21 +323,33 @@ pub fn stage(ioc: &IOC) -> io::Result<()> {
         }
     }
 
-    match ioc.ioc_type {
-        IocType::Compiled => {
-            match render::render_startup(ioc, ioc.templates.as_os_str().to_str().unwrap()) {
-                Ok(_) => debug!("{}, startup script rendered.", tick!()),
-                Err(e) => {
-                    error!(
-                        "{} failed to render startup script with: {}",
-                        cross!(),
-                        e.to_string().red()
-                    );
-                    return Err(e);
-                }
-            }
+    match render::render_ioc_templates(ioc, ioc.templates.as_os_str().to_str().unwrap(), &origin) {
+        Ok(_) => debug!("{} IOC templates rendered.", tick!()),
+        Err(e) => {
+            error!(
+                "{} failed to render IOC templates with: {}",
+                cross!(),
+                e.to_string().red()
+            );
+            return Err(e);
+        }
+    }
+
+    let template_dir = ioc.templates.as_os_str().to_str().unwrap();
+    let startup = match ioc.ioc_type {
+        IocType::Compiled => render::render_startup(ioc, template_dir, &origin),
+        IocType::Python => render::render_python_launcher(ioc, template_dir, &origin),
+    };
+    match startup {
+        Ok(_) => debug!("{}, startup script rendered.", tick!()),
+        Err(e) => {
+            error!(
+                "{} failed to render startup script with: {}",
+                cross!(),
+                e.to_string().red()
+            );
+            return Err(e);
         }
-        _ => {}
     }
 
     info!(
@@ -92,6 +362,18 @@ pub fn stage(ioc: &IOC) -> io::Result<()> {
 }
 
 fn prep_stage(ioc: &IOC) -> io::Result<()> {
+    // never clear the target of a planted link
+    if fs::symlink_metadata(&ioc.stage).is_ok_and(|m| m.file_type().is_symlink()) {
+        error!(
+            "{} stage directory {:?} is a symlink, refusing to use it",
+            cross!(),
+            ioc.stage
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "stage directory is a symlink",
+        ));
+    }
     if ioc.stage.exists() {
         warn!(
             "{} stage directory already exists, attempting to fix that.",
@@ -114,8 +396,10 @@ fn prep_stage(ioc: &IOC) -> io::Result<()> {
 
 #[cfg(test)]
 mod tests {
+    use crate::file_system::{CopyMode, LayoutSettings};
     use crate::settings::Settings;
     use crate::test_utils::new_test_ioc;
+    use serial_test::serial;
     use std::fs;
     use std::path::Path;
     use tempfile::tempdir;
@@ -177,15 +461,18 @@ mod tests {
         )
         .unwrap();
         // the actual checks
-        assert!(stage(&test_ioc).is_err());
+        assert!(stage(&test_ioc, &Deployment::new(&false, &false)).is_err());
         assert!(!&test_ioc.stage.exists());
         Ok(())
     }
 
     #[test]
+    #[serial]
     fn test_stage_ioc_struct_success() -> io::Result<()> {
-        let settings = Settings::build("./tests/config/test_stage.toml").unwrap();
-        let template_dir = settings.get::<String>("app.template_directory").unwrap();
+        let template_dir = Settings::load("./tests/config/test_stage.toml", None, &[])
+            .unwrap()
+            .app
+            .template_directory;
 
         let temp_dir = tempdir()?;
         let stage_dir = temp_dir.path().join("stage");
@@ -201,8 +488,257 @@ mod tests {
         )
         .unwrap();
         // the actual checks
-        assert!(stage(&test_ioc).is_ok());
+        assert!(stage(&test_ioc, &Deployment::new(&false, &false)).is_ok());
         assert!(&test_ioc.stage.exists());
         Ok(())
     }
+
+    #[test]
+    #[serial]
+    fn test_stage_python_ioc() -> io::Result<()> {
+        let template_dir = Settings::load("./tests/config/test_stage.toml", None, &[])
+            .unwrap()
+            .app
+            .template_directory;
+
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("PTEST_IOC01");
+        fs::create_dir_all(source.join("src"))?;
+        fs::write(
+            source.join("config.toml"),
+            "[ioc]\nhost = \"iochost\"\nport = 20000\n",
+        )?;
+        fs::write(source.join("src/main.py"), "print('hello')\n")?;
+
+        let test_ioc = IOC::new(
+            &source,
+            temp_dir.path().join("stage"),
+            temp_dir.path().join("dest"),
+            temp_dir.path().join("shellbox"),
+            template_dir,
+        )
+        .unwrap();
+        assert!(matches!(test_ioc.ioc_type, IocType::Python));
+        assert!(stage(&test_ioc, &Deployment::new(&false, &false)).is_ok());
+        assert!(test_ioc.stage.join("main.py").is_file());
+        let launcher = fs::read_to_string(test_ioc.stage.join(python_ioc::PYTHON_LAUNCHER))?;
+        assert!(launcher.contains("export IOC='PTEST_IOC01'"));
+        assert!(launcher.contains("exec python 'main.py'"));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_stage_ioc_templates_not_deployed() -> io::Result<()> {
+        let template_dir = Settings::load("./tests/config/test_stage.toml", None, &[])
+            .unwrap()
+            .app
+            .template_directory;
+
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC01");
+        file_system::copy_recursively("./tests/UTEST_IOC01", &source, CopyMode::Preserve)?;
+        fs::create_dir_all(source.join(render::IOC_TEMPLATE_DIR))?;
+        fs::write(
+            source.join(render::IOC_TEMPLATE_DIR).join("footer.tera"),
+            "# IOC FOOTER",
+        )?;
+        // not a template, dropped with a warning
+        fs::write(source.join(render::IOC_TEMPLATE_DIR).join("motor.db"), "")?;
+
+        let test_ioc = IOC::new(
+            &source,
+            temp_dir.path().join("stage"),
+            temp_dir.path().join("dest"),
+            temp_dir.path().join("shellbox"),
+            template_dir,
+        )
+        .unwrap();
+        assert!(stage(&test_ioc, &Deployment::new(&false, &false)).is_ok());
+        assert!(!test_ioc.stage.join(render::IOC_TEMPLATE_DIR).exists());
+        assert!(!test_ioc.stage.join("footer.tera").exists());
+        assert!(!test_ioc.stage.join("motor.db").exists());
+        assert!(fs::read_to_string(test_ioc.stage.join("startup.iocsh"))?.contains("# IOC FOOTER"));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_stage_site_layout() -> io::Result<()> {
+        let template_dir = Settings::load("./tests/config/test_stage.toml", None, &[])
+            .unwrap()
+            .app
+            .template_directory;
+
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC01");
+        file_system::copy_recursively("./tests/UTEST_IOC01", &source, CopyMode::Preserve)?;
+        fs::create_dir_all(source.join("db"))?;
+        fs::create_dir_all(source.join("src"))?;
+        fs::write(source.join("db/motor.db"), "")?;
+        fs::write(source.join("src/notes.txt"), "")?;
+
+        let test_ioc = IOC::new(
+            &source,
+            temp_dir.path().join("stage"),
+            temp_dir.path().join("dest"),
+            temp_dir.path().join("shellbox"),
+            template_dir,
+        )
+        .unwrap()
+        .with_site_layout(&LayoutSettings {
+            layout: None,
+            preserve: Some(vec!["cfg".to_string(), "db".to_string()]),
+            ..Default::default()
+        });
+        assert!(stage(&test_ioc, &Deployment::new(&false, &false)).is_ok());
+        assert!(test_ioc.stage.join("db/motor.db").is_file());
+        assert!(test_ioc.stage.join("notes.txt").is_file());
+        assert!(!test_ioc.stage.join("src").exists());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_stage_ignored_files() -> io::Result<()> {
+        let template_dir = Settings::load("./tests/config/test_stage.toml", None, &[])
+            .unwrap()
+            .app
+            .template_directory;
+
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC01");
+        file_system::copy_recursively("./tests/UTEST_IOC01", &source, CopyMode::Preserve)?;
+        fs::write(source.join("README.md"), "")?;
+        fs::write(source.join("st.cmd~"), "")?;
+        fs::write(source.join(file_system::IGNORE_FILE), "*~\n")?;
+
+        let test_ioc = IOC::new(
+            &source,
+            temp_dir.path().join("stage"),
+            temp_dir.path().join("dest"),
+            temp_dir.path().join("shellbox"),
+            template_dir,
+        )
+        .unwrap()
+        .with_site_ignore(&["*.md".to_string()])?;
+        assert!(stage(&test_ioc, &Deployment::new(&false, &false)).is_ok());
+        assert!(test_ioc.stage.join("startup.iocsh").is_file());
+        assert!(!test_ioc.stage.join("README.md").exists());
+        assert!(!test_ioc.stage.join("st.cmd~").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_summary() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path().join("UTEST_IOC01");
+        fs::create_dir_all(dir.join("cfg"))?;
+        fs::write(dir.join("cfg/motors.cfg"), "")?;
+        fs::write(dir.join("startup.iocsh"), "")?;
+        fs::write(dir.join("ORIGIN"), "")?;
+
+        let expected = format!(
+            "{}\n├── ORIGIN\n├── cfg\n│   └── motors.cfg\n└── startup.iocsh\n\n3 file(s)",
+            dir.display()
+        );
+        assert_eq!(tree_summary(&dir)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_ioc_stage_path() -> io::Result<()> {
+        let settings = Settings::load("./tests/config/test_stage.toml", None, &[]).unwrap();
+        let temp_dir = tempdir()?;
+        let stage_dir = temp_dir.path().join("inspect");
+        let iocs = Some(vec!["./tests/UTEST_IOC01".to_string()]);
+
+        // a single IOC into exactly that directory, a previous stage is replaced
+        let single = temp_dir.path().join("single");
+        let path = Some(single.to_str().unwrap().to_string());
+        ioc_stage(&iocs, &settings, &path, &false, &false)?;
+        assert!(single.join("startup.iocsh").is_file());
+        ioc_stage(&iocs, &settings, &path, &false, &true)?;
+        assert!(single.join("startup.iocsh").is_file());
+
+        // several IOCs into directories named after them, previous stages removed, other
+        // files kept
+        let path = Some(stage_dir.to_str().unwrap().to_string());
+        let second = temp_dir.path().join("UTEST_IOC02");
+        file_system::copy_recursively("./tests/UTEST_IOC01", &second, CopyMode::Preserve)?;
+        fs::create_dir_all(stage_dir.join("UTEST_IOC01"))?;
+        fs::write(
+            stage_dir.join("UTEST_IOC01/ORIGIN"),
+            fs::read(single.join("ORIGIN"))?,
+        )?;
+        fs::write(stage_dir.join("notes.txt"), "")?;
+        let iocs = Some(vec![
+            "./tests/UTEST_IOC01".to_string(),
+            second.to_str().unwrap().to_string(),
+        ]);
+        ioc_stage(&iocs, &settings, &path, &false, &true)?;
+        assert!(stage_dir.join("UTEST_IOC01/startup.iocsh").is_file());
+        assert!(stage_dir.join("UTEST_IOC02/startup.iocsh").is_file());
+        assert!(stage_dir.join("notes.txt").is_file());
+
+        // never clean the sources
+        let path = Some(temp_dir.path().to_str().unwrap().to_string());
+        assert!(ioc_stage(&iocs, &settings, &path, &false, &true).is_err());
+        assert!(second.join("startup.iocsh").is_file());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_ioc_stage_clean_run_dirs() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let stage_root = temp_dir.path().join("stage");
+        let sets = [format!("filesystem.stage={:?}", stage_root)];
+        let settings = Settings::load("./tests/config/test_stage.toml", None, &sets).unwrap();
+        let iocs = Some(vec!["./tests/UTEST_IOC01".to_string()]);
+        let run_dirs = || -> io::Result<usize> { Ok(fs::read_dir(&stage_root)?.count()) };
+
+        ioc_stage(&iocs, &settings, &None, &false, &false)?;
+        ioc_stage(&iocs, &settings, &None, &false, &false)?;
+        assert_eq!(run_dirs()?, 2);
+        // anything but a stage is kept
+        let other = stage_root.join("ioc-other");
+        fs::create_dir_all(&other)?;
+        fs::write(other.join("notes.txt"), "")?;
+
+        ioc_stage(&iocs, &settings, &None, &false, &true)?;
+        assert_eq!(run_dirs()?, 2);
+        assert!(other.join("notes.txt").is_file());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_ioc_stage_path_refused() -> io::Result<()> {
+        let settings = Settings::load("./tests/config/test_stage.toml", None, &[]).unwrap();
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC01");
+        file_system::copy_recursively("./tests/UTEST_IOC01", &source, CopyMode::Preserve)?;
+        let iocs = Some(vec![source.to_str().unwrap().to_string()]);
+        let stage = |dir: &Path| {
+            let path = Some(dir.to_str().unwrap().to_string());
+            ioc_stage(&iocs, &settings, &path, &false, &false)
+        };
+
+        // the stage would be the source itself, or inside of it
+        assert!(stage(temp_dir.path()).is_err());
+        assert!(stage(&source).is_err());
+        assert!(source.join("config.toml").is_file());
+        assert!(source.join("startup.iocsh").is_file());
+
+        // a directory which isn't a stage
+        let other = temp_dir.path().join("other");
+        fs::create_dir_all(&other)?;
+        fs::write(other.join("data.txt"), "")?;
+        assert!(stage(&other).is_err());
+        assert!(other.join("data.txt").is_file());
+        assert!(stage(Path::new(".")).is_err());
+        Ok(())
+    }
 }
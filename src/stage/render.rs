@@ -1,16 +1,24 @@
-use std::ffi::OsString;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as _;
+use std::os::unix::fs::PermissionsExt;
 use std::{
     fs::{self, File},
-    io::Write,
+    io::{self, Write},
+    path::{Path, PathBuf},
 };
 
 use chrono::{DateTime, Local};
 use colored::Colorize;
-use log::{error, trace};
-use tera::{Context, Error, Tera};
-// use users::get_current_username;
+use log::trace;
+use tera::{ast::Node, Context, Error, Tera, Value};
 
-use crate::{ioc::IOC, log_macros::tick, metadata::PackageData};
+use crate::{
+    ioc::{ioc_config::IocConfig, python_ioc, IocType, IOC},
+    log_macros::tick,
+    metadata::PackageData,
+    origin::{get_user_name, Origin},
+    settings::VerifyError,
+};
 
 fn base_context() -> Context {
     let metadata = PackageData::new();
@@ -23,46 +31,264 @@ fn base_context() -> Context {
     context
 }
 
-fn create_parser(template_dir: &str) -> Tera {
-    let tera = match Tera::new(template_dir) {
-        Ok(t) => t,
-        Err(e) => {
-            error!("Parsing error(s): {}", e);
-            std::process::exit(1);
+/// extension of IOC files that are rendered during staging, e.g. `st.cmd.tera` -> `st.cmd`
+pub const TEMPLATE_EXTENSION: &str = "tera";
+/// directory of an IOC definition with templates overriding the site templates
+pub const IOC_TEMPLATE_DIR: &str = "templates";
+/// directory of the site templates with host specific templates, `hosts/<host>/startup.tera`
+const HOSTS_DIR: &str = "hosts/";
+/// directory of the site templates with templates of an IOC group, `groups/<group>/startup.tera`
+const GROUPS_DIR: &str = "groups/";
+/// the site templates stay available with this prefix, e.g. `{% extends "site/startup.tera" %}`
+const SITE_PREFIX: &str = "site/";
+/// the group templates stay available with this prefix, e.g. `{% extends "group/startup.tera" %}`
+const GROUP_PREFIX: &str = "group/";
+/// the host templates stay available with this prefix, e.g. `{% extends "host/startup.tera" %}`
+const HOST_PREFIX: &str = "host/";
+
+/// filter quoting a value as a single shell word, e.g. `exec python {{ entry_point | shell_quote }}`
+const SHELL_QUOTE_FILTER: &str = "shell_quote";
+
+/// register the filters available to all templates
+pub fn register_filters(tera: &mut Tera) {
+    tera.register_filter(SHELL_QUOTE_FILTER, shell_quote);
+}
+
+/// the value in single quotes, single quotes within are closed, escaped and reopened
+fn shell_quote(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    Ok(Value::String(format!("'{}'", text.replace('\'', r"'\''"))))
+}
+
+/// Creates the tera parser for an IOC. The templates are layered, with the later layers taking
+/// precedence over the earlier ones:
+///
+/// 1. site templates from `template_dir`
+/// 2. group templates from `<template_dir>/groups/<template_group>/`, if the IOC config sets
+///    `template_group`
+/// 3. host templates from `<template_dir>/hosts/<host>/`
+/// 4. IOC templates from `<ioc source>/templates/`
+///
+/// The site, group and host templates remain available as `site/<name>`, `group/<name>` and
+/// `host/<name>`, so an override can extend the template it replaces and redefine its blocks.
+fn create_parser(template_dir: &str, ioc: &IOC) -> Result<Tera, Error> {
+    let site = Tera::new(template_dir)?;
+    let host_dir = format!("{}{}/", HOSTS_DIR, ioc.config.ioc.host);
+    let group_dir = ioc
+        .config
+        .ioc
+        .template_group
+        .as_ref()
+        .map(|group| format!("{}{}/", GROUPS_DIR, group));
+
+    let mut names: Vec<&String> = site.templates.keys().collect();
+    names.sort();
+    let mut site_layer = Vec::new();
+    let mut group_layer = Vec::new();
+    let mut host_layer = Vec::new();
+    for name in names {
+        let Some(path) = &site.templates[name].path else {
+            continue;
+        };
+        let content = fs::read_to_string(path)
+            .map_err(|e| Error::chain(format!("failed to read template {:?}", path), e))?;
+        let group_name = group_dir.as_ref().and_then(|dir| name.strip_prefix(dir));
+        if let Some(host_name) = name.strip_prefix(&host_dir) {
+            host_layer.push((format!("{}{}", HOST_PREFIX, host_name), content.clone()));
+            host_layer.push((host_name.to_string(), content));
+        } else if let Some(group_name) = group_name {
+            group_layer.push((format!("{}{}", GROUP_PREFIX, group_name), content.clone()));
+            group_layer.push((group_name.to_string(), content));
+        } else if !name.starts_with(HOSTS_DIR) && !name.starts_with(GROUPS_DIR) {
+            site_layer.push((format!("{}{}", SITE_PREFIX, name), content.clone()));
+            site_layer.push((name.to_string(), content));
         }
+    }
+    let ioc_dir = ioc.source.join(IOC_TEMPLATE_DIR);
+    let ioc_layer = match ioc_dir.is_dir() {
+        true => read_templates(&ioc_dir, &ioc_dir)
+            .map_err(|e| Error::chain(format!("failed to read templates {:?}", ioc_dir), e))?,
+        false => Vec::new(),
     };
+
+    let overrides: Vec<String> = group_layer
+        .iter()
+        .chain(host_layer.iter())
+        .chain(ioc_layer.iter())
+        .map(|(name, _)| name.to_owned())
+        .collect();
+    let mut tera = Tera::default();
+    register_filters(&mut tera);
+    tera.add_raw_templates(
+        site_layer
+            .into_iter()
+            .chain(group_layer)
+            .chain(host_layer)
+            .chain(ioc_layer),
+    )?;
+    check_blocks(&tera, &overrides)?;
     trace!("{} tera parser created", tick!());
-    tera
+    Ok(tera)
 }
 
-fn get_user_name() -> Option<OsString> {
-    match users::get_current_username() {
-        Some(uname) => Some(uname),
-        None => std::env::var_os("USER").or(Some("unkown".into())),
+/// read all templates below `dir`, named by their path relative to `root`
+fn read_templates(dir: &Path, root: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut templates = Vec::new();
+    for path in find_ioc_templates(dir)? {
+        let name = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        templates.push((name, fs::read_to_string(&path)?));
     }
+    Ok(templates)
 }
 
-fn render_startup_script(ioc: &IOC, template_dir: &str) -> Result<String, Error> {
-    let user_name = get_user_name().expect("failed to get username");
+/// make sure every block of an overriding template exists in the templates it extends
+fn check_blocks(tera: &Tera, overrides: &[String]) -> Result<(), Error> {
+    for name in overrides {
+        let template = &tera.templates[name];
+        if template.parents.is_empty() {
+            continue;
+        }
+        // only the top level blocks override something, nested ones may be new
+        let blocks = template.ast.iter().filter_map(|node| match node {
+            Node::Block(_, block, _) => Some(&block.name),
+            _ => None,
+        });
+        for block in blocks {
+            if !template
+                .parents
+                .iter()
+                .any(|parent| tera.templates[parent].blocks.contains_key(block))
+            {
+                return Err(Error::msg(format!(
+                    "template '{}' overrides block '{}', which does not exist in '{}'",
+                    name,
+                    block,
+                    template.parents.join("' -> '")
+                )));
+            }
+        }
+    }
+    Ok(())
+}
 
-    let tera = create_parser(template_dir);
+fn ioc_context(ioc: &IOC, origin: &Origin) -> Context {
+    let user_name = get_user_name();
 
     let mut context = base_context();
     context.insert("IOC", &ioc.name);
     context.insert("user", &user_name.as_os_str().to_str());
     context.insert("destination", &ioc.destination);
-    // context.insert("date", &formatted);
+    context.insert("config", &ioc.config.ioc);
+    context.insert("origin", origin);
+    context.insert("vars", &ioc.config.vars);
+    context.insert("conda_env", python_ioc::CONDA_ENV_DIR);
+    context.insert("entry_point", python_ioc::entry_point(&ioc.config.ioc));
+    trace!(
+        "{} tera context created: {:?}",
+        tick!(),
+        &context.clone().into_json()
+    );
+    context
+}
+
+/// context of a made up IOC, to dry-run the site templates
+pub fn sample_context() -> Context {
+    let config = IocConfig {
+        port: 20000,
+        host: "iochost".to_string(),
+        user: "control2".to_string(),
+        command: "iocsh".to_string(),
+        command_args: "startup.iocsh".to_string(),
+        ..Default::default()
+    };
+    let mut context = base_context();
+    context.insert("IOC", "SAMPLE_IOC01");
+    context.insert("user", &get_user_name().to_string_lossy());
+    context.insert("destination", "/ioc/SAMPLE_IOC01");
+    context.insert("config", &config);
+    context.insert("origin", &Origin::default());
+    context.insert("vars", &BTreeMap::<String, tera::Value>::new());
+    context.insert("conda_env", python_ioc::CONDA_ENV_DIR);
+    context.insert("entry_point", python_ioc::DEFAULT_ENTRY_POINT);
     trace!(
         "{} tera context created: {:?}",
         tick!(),
         &context.clone().into_json()
     );
+    context
+}
+
+/// includes of the templates that don't resolve to a template, as `(template, include)`
+pub fn unresolved_includes(tera: &Tera) -> Vec<(String, String)> {
+    fn walk<'a>(nodes: &'a [Node], includes: &mut Vec<&'a [String]>) {
+        for node in nodes {
+            match node {
+                Node::Include(_, names, false) => includes.push(names),
+                Node::Block(_, block, _) => walk(&block.body, includes),
+                Node::MacroDefinition(_, definition, _) => walk(&definition.body, includes),
+                Node::FilterSection(_, section, _) => walk(&section.body, includes),
+                Node::Forloop(_, forloop, _) => {
+                    walk(&forloop.body, includes);
+                    walk(forloop.empty_body.as_deref().unwrap_or_default(), includes);
+                }
+                Node::If(condition, _) => {
+                    condition
+                        .conditions
+                        .iter()
+                        .for_each(|(_, _, body)| walk(body, includes));
+                    if let Some((_, body)) = &condition.otherwise {
+                        walk(body, includes);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut names: Vec<&String> = tera.templates.keys().collect();
+    names.sort();
+    let mut unresolved = Vec::new();
+    for name in names {
+        let mut includes = Vec::new();
+        walk(&tera.templates[name].ast, &mut includes);
+        for alternatives in includes {
+            if !alternatives.iter().any(|i| tera.templates.contains_key(i)) {
+                unresolved.push((name.to_owned(), alternatives.join(", ")));
+            }
+        }
+    }
+    unresolved
+}
+
+/// flatten a tera error and its causes into a single line
+pub fn error_chain(e: &Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+fn render_startup_script(ioc: &IOC, template_dir: &str, origin: &Origin) -> Result<String, Error> {
+    let tera = create_parser(template_dir, ioc)?;
+    let context = ioc_context(ioc, origin);
 
     trace!("{} tera rendering ...", tick!());
     tera.render("startup.tera", &context)
 }
 
-pub fn render_startup(ioc: &IOC, template_dir: &str) -> std::io::Result<()> {
+pub fn render_startup(ioc: &IOC, template_dir: &str, origin: &Origin) -> std::io::Result<()> {
     let old = &ioc.stage.join("startup.iocsh");
     let ioc_startup = "startup.iocsh_".to_owned() + &ioc.name;
     let new = &ioc.stage.join(ioc_startup);
@@ -73,8 +299,10 @@ pub fn render_startup(ioc: &IOC, template_dir: &str) -> std::io::Result<()> {
         &old.as_path(),
         &new.as_path()
     );
+    let rendered = render_startup_script(ioc, template_dir, origin)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, error_chain(&e)))?;
     let mut file = File::create(old)?;
-    file.write_all(render_startup_script(ioc, template_dir).unwrap().as_bytes())?;
+    file.write_all(rendered.as_bytes())?;
     trace!(
         "{} template rendered and written to {:?}",
         tick!(),
@@ -83,16 +311,148 @@ pub fn render_startup(ioc: &IOC, template_dir: &str) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Render the launcher script of a Python IOC. It exports the IOC environment, activates the
+/// Conda environment and execs the entry point.
+pub fn render_python_launcher(ioc: &IOC, template_dir: &str, origin: &Origin) -> io::Result<()> {
+    let to_io_error = |e: Error| io::Error::new(io::ErrorKind::InvalidData, error_chain(&e));
+    let tera = create_parser(template_dir, ioc).map_err(to_io_error)?;
+    if !tera
+        .templates
+        .contains_key(python_ioc::PYTHON_STARTUP_TEMPLATE)
+    {
+        let error = VerifyError::MissingTemplate {
+            template: python_ioc::PYTHON_STARTUP_TEMPLATE.to_string(),
+            ioc_type: IocType::Python,
+        };
+        return Err(io::Error::new(io::ErrorKind::NotFound, error.to_string()));
+    }
+    let rendered = tera
+        .render(
+            python_ioc::PYTHON_STARTUP_TEMPLATE,
+            &ioc_context(ioc, origin),
+        )
+        .map_err(to_io_error)?;
+
+    let launcher = ioc.stage.join(python_ioc::PYTHON_LAUNCHER);
+    fs::write(&launcher, rendered)?;
+    fs::set_permissions(&launcher, fs::Permissions::from_mode(0o755))?;
+    trace!(
+        "{} template rendered and written to {:?}",
+        tick!(),
+        &launcher.as_path()
+    );
+    Ok(())
+}
+
+/// JSON of the context the templates of `ioc` are rendered with
+pub fn context_json(ioc: &IOC, origin: &Origin) -> String {
+    format!("{:#}", ioc_context(ioc, origin).into_json())
+}
+
+/// Render a single template for `ioc` without staging it. The template is either one of the
+/// (layered) site templates, e.g. `startup.tera`, or a template file of the IOC definition,
+/// e.g. `st.cmd.tera`.
+pub fn render_template(
+    ioc: &IOC,
+    template_dir: &str,
+    template: &str,
+    origin: &Origin,
+) -> io::Result<String> {
+    let to_io_error = |e: Error| io::Error::new(io::ErrorKind::InvalidData, error_chain(&e));
+    let mut tera = create_parser(template_dir, ioc).map_err(to_io_error)?;
+    let context = ioc_context(ioc, origin);
+    if tera.get_template_names().any(|name| name == template) {
+        tera.render(template, &context).map_err(to_io_error)
+    } else if ioc.source.join(template).is_file() {
+        let content = fs::read_to_string(ioc.source.join(template))?;
+        tera.render_str(&content, &context).map_err(to_io_error)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "template '{}' neither found in {:?} nor in {:?}",
+                template, template_dir, ioc.source
+            ),
+        ))
+    }
+}
+
+/// collect all files with the template extension below `dir`, sorted for a deterministic order
+fn find_ioc_templates(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    find_files(dir, true)
+}
+
+/// collect all files below `dir` which aren't templates, sorted
+pub fn find_non_templates(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    find_files(dir, false)
+}
+
+/// files below `dir` with, or without, the template extension
+fn find_files(dir: &Path, templates: bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            files.extend(find_files(&path, templates)?);
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == TEMPLATE_EXTENSION)
+            == templates
+        {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// render all `*.tera` files of the staged IOC and write them without the `.tera` suffix.
+/// The site templates are available for includes, the context is the same as for the startup
+/// script.
+pub fn render_ioc_templates(ioc: &IOC, template_dir: &str, origin: &Origin) -> io::Result<()> {
+    let templates = find_ioc_templates(&ioc.stage)?;
+    if templates.is_empty() {
+        return Ok(());
+    }
+
+    let mut tera = create_parser(template_dir, ioc)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, error_chain(&e)))?;
+    let context = ioc_context(ioc, origin);
+    for template in templates {
+        let content = fs::read_to_string(&template)?;
+        let rendered = tera.render_str(&content, &context).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "failed to render {:?}: {}",
+                    template.strip_prefix(&ioc.stage).unwrap_or(&template),
+                    error_chain(&e)
+                ),
+            )
+        })?;
+        let target = template.with_extension("");
+        fs::write(&target, rendered)?;
+        fs::remove_file(&template)?;
+        trace!(
+            "{} rendered {:?} -> {:?}",
+            tick!(),
+            template.as_path(),
+            target.as_path()
+        );
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod render_tests {
+    use crate::file_system::{copy_recursively, CopyMode};
     use crate::test_utils::new_test_ioc;
+    use tempfile::{tempdir, TempDir};
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
-    #[test]
-    fn startup() {
-        let test_ioc = new_test_ioc("./tests/UTEST_IOC01").unwrap();
-        let expected = "\
+    const TEST_STARTUP: &str = "\
 # ------------------
 # TEST HEADER
 # ------------------
@@ -105,10 +465,284 @@ mod render_tests {
 
 ";
 
+    /// copy of the test IOC with `templates` in its template directory
+    fn ioc_with_templates(templates: &[(&str, &str)]) -> io::Result<(TempDir, IOC)> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("UTEST_IOC01");
+        copy_recursively("./tests/UTEST_IOC01", &source, CopyMode::Preserve)?;
+        for (name, content) in templates {
+            let path = source.join(IOC_TEMPLATE_DIR).join(name);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, content)?;
+        }
+        let test_ioc = new_test_ioc(&source)?;
+        Ok((temp_dir, test_ioc))
+    }
+
+    #[test]
+    fn startup() {
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01").unwrap();
+        let template_dir = "./tests/render_test/templates/*.tera";
+        assert_eq!(
+            render_startup_script(&test_ioc, template_dir, &Origin::new(&test_ioc.source)).unwrap(),
+            TEST_STARTUP
+        );
+    }
+
+    #[test]
+    fn startup_ioc_override() -> io::Result<()> {
+        let (_temp_dir, test_ioc) = ioc_with_templates(&[("footer.tera", "# IOC FOOTER\n")])?;
+        let template_dir = "./tests/render_test/templates/*.tera";
+        let rendered =
+            render_startup_script(&test_ioc, template_dir, &Origin::new(&test_ioc.source)).unwrap();
+        assert!(rendered.contains("# TEST HEADER"));
+        assert!(rendered.contains("# IOC FOOTER\n"));
+        assert!(!rendered.contains("# TEST FOOTER"));
+        Ok(())
+    }
+
+    #[test]
+    fn startup_ioc_extends_site() -> io::Result<()> {
+        let (_temp_dir, test_ioc) = ioc_with_templates(&[(
+            "startup.tera",
+            "{% extends \"site/startup.tera\" %}\
+             {% block startup %}{{ super() }}dbl > pvlist.txt\n{% endblock startup %}",
+        )])?;
+        let template_dir = "./tests/render_test/templates/*.tera";
+        let rendered =
+            render_startup_script(&test_ioc, template_dir, &Origin::new(&test_ioc.source)).unwrap();
+        assert_eq!(
+            rendered,
+            TEST_STARTUP.replace(
+                "< startup.iocsh_UTEST_IOC01\n",
+                "< startup.iocsh_UTEST_IOC01\ndbl > pvlist.txt\n"
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn startup_ioc_unknown_block() -> io::Result<()> {
+        let (_temp_dir, test_ioc) = ioc_with_templates(&[(
+            "startup.tera",
+            "{% extends \"site/startup.tera\" %}{% block typo %}{% endblock typo %}",
+        )])?;
+        let template_dir = "./tests/render_test/templates/*.tera";
+        let err = render_startup_script(&test_ioc, template_dir, &Origin::new(&test_ioc.source))
+            .unwrap_err();
+        assert!(err.to_string().contains("'typo'"));
+        Ok(())
+    }
+
+    #[test]
+    fn startup_host_override() -> io::Result<()> {
+        let site_dir = tempdir()?;
+        copy_recursively(
+            "./tests/render_test/templates",
+            site_dir.path(),
+            CopyMode::Preserve,
+        )?;
+        fs::create_dir_all(site_dir.path().join("hosts/iochost"))?;
+        fs::create_dir_all(site_dir.path().join("hosts/otherhost"))?;
+        fs::write(
+            site_dir.path().join("hosts/iochost/header.tera"),
+            "# HOST\n",
+        )?;
+        fs::write(
+            site_dir.path().join("hosts/otherhost/header.tera"),
+            "# OTHER\n",
+        )?;
+        let template_dir = format!("{}/**/*.tera", site_dir.path().display());
+
+        // host overrides site
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01")?;
+        let rendered =
+            render_startup_script(&test_ioc, &template_dir, &Origin::new(&test_ioc.source))
+                .unwrap();
+        assert!(rendered.starts_with("# HOST\n"));
+
+        // IOC overrides host
+        let (_temp_dir, test_ioc) = ioc_with_templates(&[("header.tera", "# IOC\n")])?;
+        let rendered =
+            render_startup_script(&test_ioc, &template_dir, &Origin::new(&test_ioc.source))
+                .unwrap();
+        assert!(rendered.starts_with("# IOC\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn startup_group_override() -> io::Result<()> {
+        let site_dir = tempdir()?;
+        copy_recursively(
+            "./tests/render_test/templates",
+            site_dir.path(),
+            CopyMode::Preserve,
+        )?;
+        fs::create_dir_all(site_dir.path().join("groups/motors"))?;
+        fs::write(
+            site_dir.path().join("groups/motors/header.tera"),
+            "# GROUP\n",
+        )?;
+        let template_dir = format!("{}/**/*.tera", site_dir.path().display());
+
+        // without a group, the site template
+        let mut test_ioc = new_test_ioc("./tests/UTEST_IOC01")?;
+        let origin = Origin::new(&test_ioc.source);
+        let rendered = render_startup_script(&test_ioc, &template_dir, &origin).unwrap();
+        assert!(rendered.starts_with(TEST_STARTUP));
+
+        // group overrides site
+        test_ioc.config.ioc.template_group = Some("motors".to_string());
+        let rendered = render_startup_script(&test_ioc, &template_dir, &origin).unwrap();
+        assert!(rendered.starts_with("# GROUP\n"));
+
+        // host overrides group, and can extend it
+        fs::create_dir_all(site_dir.path().join("hosts/iochost"))?;
+        fs::write(
+            site_dir.path().join("hosts/iochost/header.tera"),
+            "{% include \"group/header.tera\" %}# HOST\n",
+        )?;
+        let rendered = render_startup_script(&test_ioc, &template_dir, &origin).unwrap();
+        assert!(rendered.starts_with("# GROUP\n# HOST\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn render_template_site_and_ioc() -> io::Result<()> {
+        let (_temp_dir, test_ioc) = ioc_with_templates(&[])?;
+        fs::write(
+            test_ioc.source.join("st.cmd.tera"),
+            "dbLoadRecords {{ IOC }}.db",
+        )?;
+        let template_dir = "./tests/render_test/templates/*.tera";
+        let origin = Origin::new(&test_ioc.source);
+
+        assert_eq!(
+            render_template(&test_ioc, template_dir, "startup.tera", &origin)?,
+            TEST_STARTUP
+        );
+        assert_eq!(
+            render_template(&test_ioc, template_dir, "st.cmd.tera", &origin)?,
+            "dbLoadRecords UTEST_IOC01.db"
+        );
+        assert_eq!(
+            render_template(&test_ioc, template_dir, "missing.tera", &origin)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn python_launcher() -> io::Result<()> {
+        let (_temp_dir, mut test_ioc) = ioc_with_templates(&[])?;
+        test_ioc.config.ioc.entry_point = Some("my $(ioc)'s.py".to_string());
+        fs::create_dir_all(&test_ioc.stage)?;
+
+        let template_dir = "./tests/render_test/templates/*.tera";
+        render_python_launcher(&test_ioc, template_dir, &Origin::new(&test_ioc.source))?;
+
+        let launcher = test_ioc.stage.join(python_ioc::PYTHON_LAUNCHER);
+        assert_eq!(
+            fs::read_to_string(&launcher)?,
+            "export IOC='UTEST_IOC01'\nsource \"env/bin/activate\"\n\
+             exec python 'my $(ioc)'\\''s.py'\n"
+        );
+        assert_eq!(fs::metadata(&launcher)?.permissions().mode() & 0o777, 0o755);
+
+        // a site without Python IOCs
+        let site = tempdir()?;
+        fs::write(site.path().join("startup.tera"), "{{ IOC }}")?;
+        let template_dir = format!("{}/*.tera", site.path().display());
+        let error =
+            render_python_launcher(&test_ioc, &template_dir, &Origin::new(&test_ioc.source))
+                .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("python_startup.tera"));
+        Ok(())
+    }
+
+    #[test]
+    fn shell_quoting() {
+        let quote = |value: Value| shell_quote(&value, &HashMap::new()).unwrap();
+        assert_eq!(quote(Value::from("main.py")), "'main.py'");
+        assert_eq!(quote(Value::from("\"$x`'")), r#"'"$x`'\'''"#);
+        assert_eq!(quote(Value::from(4)), "'4'");
+    }
+
+    #[test]
+    fn context_as_json() {
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01").unwrap();
+        let json = context_json(&test_ioc, &Origin::new(&test_ioc.source));
+        assert!(json.contains("\"IOC\": \"UTEST_IOC01\""));
+        assert!(json.contains("\"motor_count\": 4"));
+    }
+
+    #[test]
+    fn ioc_templates() -> io::Result<()> {
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01")?;
+        fs::create_dir_all(test_ioc.stage.join("cfg"))?;
+        fs::write(
+            test_ioc.stage.join("st.cmd.tera"),
+            "{% include \"header.tera\" %}\nepicsEnvSet IOC {{ IOC }}\n",
+        )?;
+        fs::write(
+            test_ioc.stage.join("cfg/motors.substitutions.tera"),
+            "{ \"{{ config.host }}:{{ config.port }}\" }\n",
+        )?;
+
         let template_dir = "./tests/render_test/templates/*.tera";
+        render_ioc_templates(&test_ioc, template_dir, &Origin::new(&test_ioc.source))?;
+
+        assert_eq!(
+            fs::read_to_string(test_ioc.stage.join("st.cmd"))?,
+            "# ------------------\n# TEST HEADER\n# ------------------\n\nepicsEnvSet IOC UTEST_IOC01\n"
+        );
         assert_eq!(
-            render_startup_script(&test_ioc, template_dir).unwrap(),
-            expected
+            fs::read_to_string(test_ioc.stage.join("cfg/motors.substitutions"))?,
+            "{ \"iochost:12345\" }\n"
         );
+        assert!(!test_ioc.stage.join("st.cmd.tera").exists());
+        assert!(!test_ioc
+            .stage
+            .join("cfg/motors.substitutions.tera")
+            .exists());
+        Ok(())
+    }
+
+    #[test]
+    fn ioc_templates_vars_and_origin() -> io::Result<()> {
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01")?;
+        fs::create_dir_all(&test_ioc.stage)?;
+        fs::write(
+            test_ioc.stage.join("vars.tera"),
+            "{{ vars.motor_count }} {{ config.command }} {{ origin.dirty is defined }}",
+        )?;
+
+        let template_dir = "./tests/render_test/templates/*.tera";
+        render_ioc_templates(&test_ioc, template_dir, &Origin::new(&test_ioc.source))?;
+
+        assert_eq!(
+            fs::read_to_string(test_ioc.stage.join("vars"))?,
+            "4 iocsh true"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ioc_templates_error() -> io::Result<()> {
+        let test_ioc = new_test_ioc("./tests/UTEST_IOC01")?;
+        fs::create_dir_all(&test_ioc.stage)?;
+        fs::write(
+            test_ioc.stage.join("broken.tera"),
+            "{{ undefined_variable }}",
+        )?;
+
+        let template_dir = "./tests/render_test/templates/*.tera";
+        let err = render_ioc_templates(&test_ioc, template_dir, &Origin::new(&test_ioc.source))
+            .unwrap_err();
+        assert!(err.to_string().contains("broken.tera"));
+        Ok(())
     }
 }
@@ -0,0 +1,256 @@
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+use std::{io, process};
+
+use colored::Colorize;
+use log::{debug, error, trace};
+use tempfile::TempDir;
+
+use crate::log_macros::{cross, tick};
+
+/// prefix of the per-run stage directories
+pub(crate) const RUN_DIR_PREFIX: &str = "ioc-";
+
+/// run directories to remove when interrupted
+static ACTIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static HANDLER: Once = Once::new();
+
+/// Private stage directory of a single run, created below the stage root with a unique name
+/// and mode 0700. It is removed when dropped, including unwinding from a panic, and by the
+/// Ctrl-C handler.
+#[derive(Debug)]
+pub struct RunDir {
+    dir: Option<TempDir>,
+}
+
+impl RunDir {
+    pub fn create(stage_root: impl AsRef<Path>) -> io::Result<RunDir> {
+        let stage_root = stage_root.as_ref();
+        check_stage_root(stage_root)?;
+        let dir = tempfile::Builder::new()
+            .prefix(RUN_DIR_PREFIX)
+            .tempdir_in(stage_root)?;
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))?;
+        ACTIVE.lock().unwrap().push(dir.path().to_path_buf());
+        install_interrupt_handler();
+        trace!("{} created run directory {:?}", tick!(), dir.path());
+        Ok(RunDir { dir: Some(dir) })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_ref().map(TempDir::path).unwrap()
+    }
+
+    /// keep the directory, e.g. for inspection, and return its path
+    pub fn keep(mut self) -> PathBuf {
+        let path = self.dir.take().unwrap().keep();
+        unregister(&path);
+        path
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            let path = dir.path().to_path_buf();
+            unregister(&path);
+            match dir.close() {
+                Ok(_) => debug!("{} removed run directory {:?}", tick!(), path),
+                Err(e) => error!("{} failed to remove {:?}: {}", cross!(), path, e),
+            }
+        }
+    }
+}
+
+fn unregister(path: &Path) {
+    if let Ok(mut active) = ACTIVE.lock() {
+        active.retain(|p| p != path);
+    }
+}
+
+/// Ctrl-C removes the run directories before exiting
+fn install_interrupt_handler() {
+    HANDLER.call_once(|| {
+        let result = ctrlc::set_handler(|| {
+            if let Ok(active) = ACTIVE.lock() {
+                for path in active.iter() {
+                    let _ = fs::remove_dir_all(path);
+                }
+            }
+            process::exit(130);
+        });
+        if let Err(e) = result {
+            error!("{} failed to install the Ctrl-C handler: {}", cross!(), e);
+        }
+    });
+}
+
+/// Directory of the user in the shared `/tmp`, holding the stage root if `XDG_RUNTIME_DIR`
+/// isn't set.
+pub fn private_tmp_dir() -> PathBuf {
+    PathBuf::from(format!("/tmp/ioc-{}", users::get_current_uid()))
+}
+
+/// The stage root is created private if missing, its missing parents with the usual
+/// permissions, they may be shared with other users. It must be a directory owned by the current
+/// user, a symlink is not followed. Below `private_tmp_dir`, that directory must be private to
+/// the user as well, otherwise another user could swap the stage root.
+pub fn check_stage_root(stage_root: &Path) -> io::Result<()> {
+    check_stage_root_in(stage_root, &private_tmp_dir())
+}
+
+fn check_stage_root_in(stage_root: &Path, private_dir: &Path) -> io::Result<()> {
+    if stage_root.starts_with(private_dir) && stage_root != private_dir {
+        create_private_dir(private_dir)?;
+        verify_owned_dir(private_dir, "private directory")?;
+        let mode = fs::symlink_metadata(private_dir)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            fs::set_permissions(private_dir, fs::Permissions::from_mode(0o700))?;
+        }
+    }
+    if fs::symlink_metadata(stage_root).is_err() {
+        create_private_dir(stage_root)?;
+    }
+    verify_stage_root(stage_root)
+}
+
+/// create `dir` with mode 0700, its missing parents with the usual permissions
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(dir).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match DirBuilder::new().mode(0o700).create(dir) {
+        // created by a concurrent run, verified by the caller
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+/// The existing stage root is a directory owned by the current user, not a symlink.
+pub fn verify_stage_root(stage_root: &Path) -> io::Result<()> {
+    verify_owned_dir(stage_root, "stage root")
+}
+
+/// `dir` is a directory owned by the current user, not a symlink
+fn verify_owned_dir(dir: &Path, name: &str) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    let refuse = |reason: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("refusing {} {:?}: {}", name, dir, reason),
+        ))
+    };
+    if metadata.file_type().is_symlink() {
+        return refuse("it is a symlink");
+    }
+    if !metadata.is_dir() {
+        return refuse("not a directory");
+    }
+    if metadata.uid() != users::get_current_uid() {
+        return refuse("owned by another user");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn run_dir_lifecycle() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let stage_root = temp_dir.path().join("ioc/stage");
+
+        let run = RunDir::create(&stage_root)?;
+        let path = run.path().to_path_buf();
+        assert!(path.starts_with(&stage_root));
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o700);
+        assert_eq!(
+            fs::metadata(&stage_root)?.permissions().mode() & 0o777,
+            0o700
+        );
+        // the parents aren't private, other users may have their stage root next to it
+        let parent_mode = fs::metadata(stage_root.parent().unwrap())?
+            .permissions()
+            .mode();
+        assert_ne!(parent_mode & 0o077, 0);
+        assert!(ACTIVE.lock().unwrap().contains(&path));
+
+        // unique per run
+        let other = RunDir::create(&stage_root)?;
+        assert_ne!(other.path(), path);
+
+        drop(run);
+        assert!(!path.exists());
+        assert!(!ACTIVE.lock().unwrap().contains(&path));
+
+        let kept = other.keep();
+        assert!(kept.is_dir());
+        assert!(!ACTIVE.lock().unwrap().contains(&kept));
+        Ok(())
+    }
+
+    #[test]
+    fn run_dir_removed_on_panic() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let stage_root = temp_dir.path().to_path_buf();
+        let result = std::panic::catch_unwind(|| {
+            let run = RunDir::create(&stage_root).unwrap();
+            fs::write(run.path().join("startup.iocsh"), "").unwrap();
+            panic!("staging failed");
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(&stage_root)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn stage_root_private_dir() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let private_dir = temp_dir.path().join("ioc-1000");
+        let stage_root = private_dir.join("stage");
+
+        check_stage_root_in(&stage_root, &private_dir)?;
+        let mode =
+            |dir: &Path| -> io::Result<u32> { Ok(fs::metadata(dir)?.permissions().mode() & 0o777) };
+        assert_eq!(mode(&private_dir)?, 0o700);
+        assert_eq!(mode(&stage_root)?, 0o700);
+
+        // made private again, e.g. created by an older version
+        fs::set_permissions(&private_dir, fs::Permissions::from_mode(0o755))?;
+        check_stage_root_in(&stage_root, &private_dir)?;
+        assert_eq!(mode(&private_dir)?, 0o700);
+
+        // planted by someone else
+        fs::remove_dir_all(&private_dir)?;
+        let target = temp_dir.path().join("elsewhere");
+        fs::create_dir_all(target.join("stage"))?;
+        symlink(&target, &private_dir)?;
+        let error = check_stage_root_in(&stage_root, &private_dir).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("private directory"));
+        Ok(())
+    }
+
+    #[test]
+    fn stage_root_symlink_refused() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let target = temp_dir.path().join("elsewhere");
+        fs::create_dir_all(&target)?;
+        let stage_root = temp_dir.path().join("stage");
+        symlink(&target, &stage_root)?;
+
+        let error = RunDir::create(&stage_root).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs::read_dir(&target)?.count(), 0);
+        Ok(())
+    }
+}